@@ -0,0 +1,731 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::events::ChatEvent;
+use crate::health;
+use crate::message::Message;
+use crate::presence::PresenceState;
+use crate::tokens::Scope;
+use crate::{config, database, metrics, Outgoing, Shared};
+
+/// Serves the HTTP API on the configured address until the server stops.
+pub async fn serve(state: Arc<Mutex<Shared>>, conn: Arc<Mutex<Connection>>) {
+    let Some(addr) = config::get().api_addr else {
+        return;
+    };
+    if !config::get().api_tokens.is_empty() {
+        let name = &config::get().api_bot_name;
+        if let Err(e) = database::ensure_api_bot(&conn, name).await {
+            tracing::error!("failed to create the API bot account {}: {:?}", name, e);
+        }
+    }
+    let limiter = HookLimiter::default();
+    let make_service = make_service_fn(move |socket: &AddrStream| {
+        let remote = socket.remote_addr();
+        let state = state.clone();
+        let conn = conn.clone();
+        let limiter = limiter.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(state.clone(), conn.clone(), limiter.clone(), remote, req)
+            }))
+        }
+    });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            tracing::error!("failed to bind the HTTP API to {}: {:?}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("HTTP API listening on {}", addr);
+    if let Err(e) = server.await {
+        tracing::error!("HTTP API stopped; error = {:?}", e);
+    }
+}
+
+/// An error answered with a JSON body of the form `{"error": "..."}`.
+#[derive(Debug)]
+enum ApiError {
+    Unauthorized,
+    Forbidden(String),
+    NotFound,
+    TooManyRequests,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    BadRequest(String),
+    Internal(Box<dyn Error + Send + Sync>),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized => write!(f, "missing or invalid API token"),
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::TooManyRequests => write!(f, "rate limit exceeded, try again later"),
+            ApiError::MethodNotAllowed => write!(f, "method not allowed"),
+            ApiError::PayloadTooLarge => {
+                write!(f, "request body is larger than {} bytes", MAX_BODY)
+            }
+            ApiError::Forbidden(reason) | ApiError::BadRequest(reason) => write!(f, "{}", reason),
+            ApiError::Internal(_) => write!(f, "internal server error"),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::Internal(Box::new(e))
+    }
+}
+
+impl From<hyper::Error> for ApiError {
+    fn from(e: hyper::Error) -> Self {
+        ApiError::Internal(Box::new(e))
+    }
+}
+
+type ApiResult = Result<Response<Body>, ApiError>;
+
+async fn handle(
+    state: Arc<Mutex<Shared>>,
+    conn: Arc<Mutex<Connection>>,
+    limiter: HookLimiter,
+    remote: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let response = match route(&state, &conn, &limiter, remote, req).await {
+        Ok(response) => response,
+        Err(e) => {
+            if let ApiError::Internal(cause) = &e {
+                tracing::error!("{} {} failed; error = {:?}", method, path, cause);
+            }
+            json(e.status(), &serde_json::json!({ "error": e.to_string() }))
+        }
+    };
+    tracing::info!("{} {} {} from {}", method, path, response.status(), remote);
+    Ok(response)
+}
+
+async fn route(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    limiter: &HookLimiter,
+    remote: SocketAddr,
+    req: Request<Body>,
+) -> ApiResult {
+    let path: Vec<String> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(str::to_string)
+        .collect();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    if path == ["metrics"] {
+        if req.method() != Method::GET {
+            return Err(ApiError::MethodNotAllowed);
+        }
+        let body = metrics::render(&*state.lock().await);
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap());
+    }
+    if path == ["healthz"] || path == ["readyz"] {
+        if req.method() != Method::GET {
+            return Err(ApiError::MethodNotAllowed);
+        }
+        let report = health::check(conn, path == ["readyz"]).await;
+        let status = if report.status == "ok" {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        return Ok(json(status, &report));
+    }
+    if let ["hooks", secret] = path.as_slice() {
+        if req.method() != Method::POST {
+            return Err(ApiError::MethodNotAllowed);
+        }
+        return incoming_webhook(state, conn, limiter, remote, secret, req).await;
+    }
+    let caller = authorize(conn, &req).await?;
+    match (req.method(), path.as_slice()) {
+        (&Method::GET, ["api", "users"]) => {
+            caller.require(Scope::Read)?;
+            list_users(state, conn).await
+        }
+        (&Method::GET, ["api", "messages"]) => {
+            caller.require(Scope::Read)?;
+            list_messages(conn, &req).await
+        }
+        (&Method::GET, ["api", "events"]) => {
+            caller.require(Scope::Read)?;
+            stream_events(state, conn, &req).await
+        }
+        (&Method::POST, ["api", "messages"]) => {
+            caller.require(Scope::Chat)?;
+            post_message(state, conn, &caller, remote, req).await
+        }
+        (&Method::POST, ["api", "users", username, action]) => {
+            caller.require(Scope::Moderate)?;
+            moderate(state, conn, &caller, username, action).await
+        }
+        (_, ["api", "users"]) | (_, ["api", "messages"]) | (_, ["api", "events"]) => {
+            Err(ApiError::MethodNotAllowed)
+        }
+        _ => Err(ApiError::NotFound),
+    }
+}
+
+/// Who is calling the API and what they may do.
+struct Caller {
+    username: String,
+    scopes: Vec<Scope>,
+    /// Whether a token from the configuration was used.
+    configured: bool,
+}
+
+impl Caller {
+    fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(format!(
+                "token lacks the {} scope",
+                scope
+            )))
+        }
+    }
+}
+
+/// Checks the `Authorization: Bearer <token>` header, or the `access_token`
+/// query parameter for browsers' `EventSource`, which cannot set headers.
+/// Tokens from the configuration act as the configured bot with every scope,
+/// database tokens act as their account.
+async fn authorize(conn: &Arc<Mutex<Connection>>, req: &Request<Body>) -> Result<Caller, ApiError> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or_else(|| query_params(req).remove("access_token"))
+        .ok_or(ApiError::Unauthorized)?;
+    let token = token.as_str();
+    if config::get().api_tokens.iter().any(|t| t == token) {
+        let name = &config::get().api_bot_name;
+        let profile = database::get_user_profile(conn, name).await?;
+        return match profile {
+            Some(profile) if profile.bot && profile.role != "banned" && !profile.locked => {
+                Ok(Caller {
+                    username: profile.username,
+                    scopes: Scope::ALL.to_vec(),
+                    configured: true,
+                })
+            }
+            Some(profile) if profile.bot => Err(ApiError::Forbidden(
+                "account is banned or locked".to_string(),
+            )),
+            _ => Err(ApiError::Forbidden(format!(
+                "the API bot account {} does not exist",
+                name
+            ))),
+        };
+    }
+    let info = database::authenticate_token(conn, token)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let profile = database::get_user_profile(conn, &info.username)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    if profile.role == "banned" || profile.locked {
+        return Err(ApiError::Forbidden(
+            "account is banned or locked".to_string(),
+        ));
+    }
+    Ok(Caller {
+        username: info.username,
+        scopes: info.scopes,
+        configured: false,
+    })
+}
+
+#[derive(Serialize)]
+struct ApiUser {
+    username: String,
+    role: String,
+    online: bool,
+    presence: Option<PresenceState>,
+    status: Option<String>,
+}
+
+async fn list_users(state: &Arc<Mutex<Shared>>, conn: &Arc<Mutex<Connection>>) -> ApiResult {
+    let (users, _) = database::find_users(conn, &Default::default(), i64::MAX, 0).await?;
+    let state = state.lock().await;
+    let users: Vec<ApiUser> = users
+        .into_iter()
+        .map(|user| {
+            let online = state.is_user_connected(&user.username);
+            let presence = online.then(|| {
+                state
+                    .presence
+                    .get(&user.username)
+                    .cloned()
+                    .unwrap_or_default()
+            });
+            ApiUser {
+                online,
+                presence: presence.as_ref().map(|p| p.state),
+                status: presence.and_then(|p| p.status.or(p.away_message)),
+                username: user.username,
+                role: user.role,
+            }
+        })
+        .collect();
+    Ok(json(StatusCode::OK, &users))
+}
+
+#[derive(Serialize)]
+struct MessagePage {
+    messages: Vec<Message>,
+    /// Pass as `before` to fetch older messages, `null` once the oldest is reached.
+    next_before: Option<i64>,
+}
+
+/// `GET /api/messages?limit=<n>&before=<id>`, newest page first.
+async fn list_messages(conn: &Arc<Mutex<Connection>>, req: &Request<Body>) -> ApiResult {
+    let query = query_params(req);
+    let number = |name: &str| -> Result<Option<i64>, ApiError> {
+        query
+            .get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| ApiError::BadRequest(format!("invalid {}", name)))
+            })
+            .transpose()
+    };
+    let limit = number("limit")?.unwrap_or(50).clamp(1, 500);
+    let before = number("before")?;
+    let messages = database::get_messages_page(conn, before, limit).await?;
+    let next_before = match messages.first() {
+        Some(first) if messages.len() as i64 == limit => first.id,
+        _ => None,
+    };
+    Ok(json(
+        StatusCode::OK,
+        &MessagePage {
+            messages,
+            next_before,
+        },
+    ))
+}
+
+/// Comment sent on idle event streams, so proxies keep them open and
+/// closed clients are noticed.
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+/// Most messages replayed to a client resuming an event stream.
+const EVENT_REPLAY_LIMIT: usize = 500;
+
+/// `GET /api/events?events=<names>`, a Server-Sent Events stream of chat
+/// events, optionally limited to the given event names. Messages carry
+/// their id, so a client reconnecting with `Last-Event-ID` first gets the
+/// messages it missed. If it missed more than can be replayed, it gets the
+/// newest ones after a `replay_truncated` event.
+async fn stream_events(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    req: &Request<Body>,
+) -> ApiResult {
+    let query = query_params(req);
+    let names: Vec<String> = query
+        .get("events")
+        .map(|names| {
+            names
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(name) = names
+        .iter()
+        .find(|name| !ChatEvent::NAMES.contains(&name.as_str()))
+    {
+        return Err(ApiError::BadRequest(format!("unknown event {}", name)));
+    }
+    let wanted = move |name: &str| names.is_empty() || names.iter().any(|n| n == name);
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .or(query.get("last_event_id").map(String::as_str))
+        .map(|id| {
+            id.trim()
+                .parse::<i64>()
+                .map_err(|_| ApiError::BadRequest("invalid Last-Event-ID".to_string()))
+        })
+        .transpose()?;
+
+    // Subscribe before reading the backlog, so nothing falls in between.
+    let mut events = state.lock().await.events.subscribe();
+    let mut missed = match last_event_id {
+        Some(id) if wanted("message_posted") => {
+            database::get_messages_after(conn, id, EVENT_REPLAY_LIMIT as i64 + 1).await?
+        }
+        _ => Vec::new(),
+    };
+    let truncated = missed.len() > EVENT_REPLAY_LIMIT;
+    if truncated {
+        missed.remove(0);
+    }
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut last_sent = last_event_id.unwrap_or(0);
+        if truncated {
+            let data = serde_json::json!({
+                "last_event_id": last_sent,
+                "replayed_from": missed.first().and_then(|message| message.id),
+            });
+            let frame = format!("event: replay_truncated\ndata: {}\n\n", data);
+            if sender.send_data(frame.into()).await.is_err() {
+                return;
+            }
+        }
+        for message in missed {
+            last_sent = message.id.unwrap_or(last_sent);
+            let frame = event_frame(&ChatEvent::MessagePosted { message });
+            if sender.send_data(frame.into()).await.is_err() {
+                return;
+            }
+        }
+        let mut keepalive = tokio::time::interval(EVENT_KEEPALIVE);
+        keepalive.tick().await;
+        loop {
+            let frame = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if !wanted(event.name()) => continue,
+                    Ok(event) if event.message_id().is_some_and(|id| id <= last_sent) => continue,
+                    Ok(event) => event_frame(&event),
+                    Err(RecvError::Lagged(skipped)) => format!(": {} events were dropped\n\n", skipped),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+            };
+            if sender.send_data(frame.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap())
+}
+
+fn event_frame(event: &ChatEvent) -> String {
+    let id = event
+        .message_id()
+        .map(|id| format!("id: {}\n", id))
+        .unwrap_or_default();
+    format!(
+        "{}event: {}\ndata: {}\n\n",
+        id,
+        event.name(),
+        event.to_json()
+    )
+}
+
+#[derive(Deserialize)]
+struct NewMessage {
+    content: String,
+    reply_to: Option<i64>,
+}
+
+/// `POST /api/messages` with `{"content": "...", "reply_to": <id>}`, sent as
+/// the calling account.
+async fn post_message(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    caller: &Caller,
+    remote: SocketAddr,
+    req: Request<Body>,
+) -> ApiResult {
+    let body = read_body(req).await?;
+    let new: NewMessage = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid message: {}", e)))?;
+    let content = message_content(&new.content)?;
+
+    let mut message = Message::from_input(caller.username.clone(), content);
+    if let Some(parent_id) = new.reply_to {
+        if database::get_message(conn, parent_id).await?.is_none() {
+            return Err(ApiError::BadRequest(format!(
+                "message #{} not found",
+                parent_id
+            )));
+        }
+        message = message.reply_to(parent_id);
+    }
+    let message = deliver(state, conn, remote, &caller.username, message).await?;
+    Ok(json(StatusCode::CREATED, &message))
+}
+
+/// Stores a message from a bot or hook and broadcasts it like one typed in
+/// the chat. Muted senders are refused.
+async fn deliver(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    remote: SocketAddr,
+    sender: &str,
+    mut message: Message,
+) -> Result<Message, ApiError> {
+    if database::get_user_role(conn, sender).await? == "muted" {
+        return Err(ApiError::Forbidden("account is muted".to_string()));
+    }
+    let id = database::store_message(conn, &mut message).await?;
+    database::store_mentions(conn, id, &message.mentions()).await?;
+    let color = database::get_user_settings(conn, sender).await?.color();
+    state
+        .lock()
+        .await
+        .broadcast_message(remote, &message, color)
+        .await;
+    Ok(message)
+}
+
+/// Recent deliveries per incoming webhook, for rate limiting.
+type HookLimiter = Arc<std::sync::Mutex<HashMap<i64, VecDeque<Instant>>>>;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// `POST /hooks/<secret>` with a plain text body or `{"content": "..."}`,
+/// posted as the hook's bot account.
+async fn incoming_webhook(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    limiter: &HookLimiter,
+    remote: SocketAddr,
+    secret: &str,
+    req: Request<Body>,
+) -> ApiResult {
+    let hook = database::find_incoming_webhook(conn, secret)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    // The hook only posts while its bot account still exists as a bot.
+    let bot = database::get_user_profile(conn, &hook.name)
+        .await?
+        .filter(|profile| profile.bot)
+        .ok_or(ApiError::NotFound)?;
+    if bot.role == "banned" || bot.locked {
+        return Err(ApiError::Forbidden(
+            "bot account is banned or locked".to_string(),
+        ));
+    }
+    {
+        let mut limiter = limiter.lock().unwrap();
+        let hits = limiter.entry(hook.id).or_default();
+        while hits.front().is_some_and(|hit| hit.elapsed() >= RATE_WINDOW) {
+            hits.pop_front();
+        }
+        if hits.len() >= hook.rate_limit as usize {
+            return Err(ApiError::TooManyRequests);
+        }
+        hits.push_back(Instant::now());
+    }
+
+    let is_json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let body = read_body(req).await?;
+    let text = if is_json {
+        let new: NewMessage = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::BadRequest(format!("invalid message: {}", e)))?;
+        new.content
+    } else {
+        String::from_utf8(body.to_vec())
+            .map_err(|_| ApiError::BadRequest("body is not UTF-8".to_string()))?
+    };
+    let content = message_content(&text)?;
+
+    let message = Message::from_input(hook.name.clone(), content);
+    let message = deliver(state, conn, remote, &hook.name, message).await?;
+    tracing::info!(
+        "incoming webhook {} posted message {:?}",
+        hook.id,
+        message.id
+    );
+    Ok(json(StatusCode::CREATED, &message))
+}
+
+/// `POST /api/users/<name>/<ban|unban|mute|unmute>`.
+async fn moderate(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    caller: &Caller,
+    username: &str,
+    action: &str,
+) -> ApiResult {
+    if !caller.configured && database::get_user_role(conn, &caller.username).await? != "admin" {
+        return Err(ApiError::Forbidden("only admins can moderate".to_string()));
+    }
+    let role = match action {
+        "ban" => "banned",
+        "mute" => "muted",
+        "unban" | "unmute" => "user",
+        _ => return Err(ApiError::NotFound),
+    };
+    let profile = database::get_user_profile(conn, username)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    database::change_role(conn, username, role).await?;
+    if action == "ban" {
+        let state = state.lock().await;
+        state
+            .send_to_user(
+                username,
+                Outgoing::Disconnect("You have been banned.".red().to_string()),
+            )
+            .await;
+        state.publish(ChatEvent::UserBanned {
+            username: username.to_string(),
+            by: caller.username.clone(),
+        });
+    }
+    tracing::info!(
+        "{} {} {} through the API",
+        caller.username,
+        action,
+        username
+    );
+    Ok(json(
+        StatusCode::OK,
+        &serde_json::json!({
+            "username": profile.username,
+            "previous_role": profile.role,
+            "role": role,
+        }),
+    ))
+}
+
+/// Longest message text accepted through the API or a hook.
+const MAX_MESSAGE: usize = 2000;
+/// Largest request body read into memory.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Buffers the request body, refusing one larger than [`MAX_BODY`] from its
+/// `Content-Length` or once that much has been read.
+async fn read_body(req: Request<Body>) -> Result<Vec<u8>, ApiError> {
+    if req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+        .is_some_and(|length| length > MAX_BODY)
+    {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    let mut body = req.into_body();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > MAX_BODY {
+            return Err(ApiError::PayloadTooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+/// Collapses whitespace, line breaks included, into single spaces and
+/// refuses other control characters, so posted text cannot forge lines or
+/// colors on telnet clients.
+fn message_content(text: &str) -> Result<String, ApiError> {
+    let content = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if content.is_empty() {
+        return Err(ApiError::BadRequest("content is empty".to_string()));
+    }
+    if content.chars().any(char::is_control) {
+        return Err(ApiError::BadRequest(
+            "content contains control characters".to_string(),
+        ));
+    }
+    if content.chars().count() > MAX_MESSAGE {
+        return Err(ApiError::BadRequest(format!(
+            "content is longer than {} characters",
+            MAX_MESSAGE
+        )));
+    }
+    Ok(content)
+}
+
+/// The percent-decoded query parameters of a request.
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    let query = req.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap_or_default()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params_are_percent_decoded() {
+        let req =
+            Request::get("/api/events?events=message_posted%2cuser_joined&access_token=a%2Bb+c%2C")
+                .body(Body::empty())
+                .unwrap();
+        let query = query_params(&req);
+        assert_eq!(query["events"], "message_posted,user_joined");
+        assert_eq!(query["access_token"], "a+b c,");
+    }
+}
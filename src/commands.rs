@@ -1,11 +1,598 @@
-use rusqlite::Connection;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
 
+use chrono::{DateTime, FixedOffset};
+use colored::{Color, Colorize};
+use prettytable::{format, row, Table};
+use rusqlite::{Connection, Result as SqlResult};
+use tokio::sync::Mutex;
 
+use crate::database::{self, IncomingWebhook, TokenInfo, UserFilter, UserProfile};
+use crate::message::Message;
+use crate::presence::Presence;
+use crate::settings::UserSettings;
+use crate::tokens::{self, Scope};
 
-pub fn format_message_history(user: &str, messages: &[String]) -> String {
+pub fn format_message_history(
+    user: &str,
+    messages: &[Message],
+    colors: &HashMap<String, Color>,
+    reactions: &HashMap<i64, Vec<(String, i64)>>,
+    settings: &UserSettings,
+) -> String {
     let mut response = format!("Message history for {}:\n\r", user);
     for message in messages {
-        response.push_str(&format!("{}\n\r", message));
+        response.push_str(&history_line(message, colors, reactions, settings));
     }
     response
 }
+
+pub fn format_mentions(
+    messages: &[Message],
+    colors: &HashMap<String, Color>,
+    reactions: &HashMap<i64, Vec<(String, i64)>>,
+    settings: &UserSettings,
+) -> String {
+    if messages.is_empty() {
+        return "Nobody has mentioned you yet.".to_string();
+    }
+    let mut response = "Recent mentions:\n\r".to_string();
+    for message in messages {
+        response.push_str(&history_line(message, colors, reactions, settings));
+    }
+    response
+}
+
+fn history_line(
+    message: &Message,
+    colors: &HashMap<String, Color>,
+    reactions: &HashMap<i64, Vec<(String, i64)>>,
+    settings: &UserSettings,
+) -> String {
+    let color = colors.get(&message.sender).copied().unwrap_or(Color::Green);
+    let counts = message
+        .id
+        .and_then(|id| reactions.get(&id))
+        .map(|counts| format_reaction_counts(counts))
+        .unwrap_or_default();
+    let message = message.clone().localize(&settings.offset()).format(color);
+    format!("{}{}\n\r", message, counts)
+}
+
+fn format_reaction_counts(counts: &[(String, i64)]) -> String {
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(emoji, count)| format!("{} {}", emoji, count))
+        .collect();
+    format!("  [{}]", counts.join("  "))
+}
+
+/// Renders a live chat message for a recipient according to their settings,
+/// highlighting and ringing the bell when they are mentioned.
+pub fn render_message(
+    message: &Message,
+    color: Color,
+    recipient: &str,
+    settings: &UserSettings,
+) -> String {
+    let mentioned = message.mentions().iter().any(|m| m == recipient);
+    let mut message = message
+        .clone()
+        .localize(&settings.offset())
+        .highlight_mentions(recipient);
+    if !settings.show_ids {
+        message.id = None;
+    }
+    let bell = if mentioned && settings.notifications {
+        "\x07"
+    } else {
+        ""
+    };
+    format!("{}{}", bell, message.format(color))
+}
+
+pub fn format_settings(settings: &UserSettings, width: Option<u16>) -> String {
+    let mut table = Table::new();
+    table.add_row(row!["Setting", "Value"]);
+    table.add_row(row!["color", settings.color.color(settings.color())]);
+    table.add_row(row!["timezone", settings.timezone]);
+    table.add_row(row![
+        "notifications",
+        if settings.notifications { "on" } else { "off" }
+    ]);
+    table.add_row(row!["ids", if settings.show_ids { "on" } else { "off" }]);
+
+    render_table(&mut table, width)
+}
+
+/// Prints a table, dropping the borders when it would be wider than the
+/// client's terminal as reported through telnet NAWS.
+pub fn render_table(table: &mut Table, width: Option<u16>) -> String {
+    let render = |table: &Table| {
+        let mut response = Vec::new();
+        table.print(&mut response).unwrap();
+        String::from_utf8(response).unwrap()
+    };
+    let response = render(table);
+    let too_wide = width.is_some_and(|width| {
+        response
+            .lines()
+            .any(|line| visible_width(line) > usize::from(width))
+    });
+    if !too_wide {
+        return response;
+    }
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    render(table)
+}
+
+/// Number of characters in `line`, not counting ANSI color escapes.
+fn visible_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// Everything after the command word, or `None` if that is blank.
+pub fn argument_text(msg: &str) -> Option<String> {
+    msg.trim()
+        .split_once(' ')
+        .map(|(_, rest)| rest.trim().to_string())
+        .filter(|rest| !rest.is_empty())
+}
+
+/// Accepts message ids with or without the leading `#` shown in history.
+pub fn parse_message_id(id: &str) -> Option<i64> {
+    id.trim_start_matches('#').parse().ok()
+}
+
+/// Returns why `username` may not edit or delete message `id`, if anything.
+pub async fn check_message_access(
+    conn: &Mutex<Connection>,
+    username: &str,
+    id: i64,
+) -> SqlResult<Option<&'static str>> {
+    match database::get_message_owner(conn, id).await? {
+        None => Ok(Some("Message not found.")),
+        Some(owner) if owner == username => Ok(None),
+        Some(_) if database::get_user_role(conn, username).await? == "admin" => Ok(None),
+        Some(_) => Ok(Some("You can only change your own messages.")),
+    }
+}
+
+/// Quotes the start of `parent` above a reply to it.
+pub fn format_quote(parent: &Message, color: Color) -> String {
+    format!(
+        "  {} {}: {}",
+        "┌".bright_black(),
+        parent.sender.color(color),
+        parent.preview(40).bright_black()
+    )
+}
+
+/// Renders a thread as an indented tree, replies below their parents.
+pub fn format_thread(
+    messages: &[Message],
+    colors: &HashMap<String, Color>,
+    settings: &UserSettings,
+) -> String {
+    let mut children: HashMap<Option<i64>, Vec<&Message>> = HashMap::new();
+    for message in messages {
+        children.entry(message.parent_id).or_default().push(message);
+    }
+
+    let mut response = String::new();
+    let mut stack: Vec<(&Message, usize)> = children
+        .get(&None)
+        .map(|roots| roots.iter().rev().map(|m| (*m, 0)).collect())
+        .unwrap_or_default();
+    while let Some((message, depth)) = stack.pop() {
+        let color = colors.get(&message.sender).copied().unwrap_or(Color::Green);
+        let mut line = message.clone().localize(&settings.offset());
+        line.parent_id = None;
+        response.push_str(&format!("{}{}\n\r", "  ".repeat(depth), line.format(color)));
+        if let Some(replies) = children.get(&message.id) {
+            stack.extend(replies.iter().rev().map(|m| (*m, depth + 1)));
+        }
+    }
+    response
+}
+
+/// A live connection of the user being looked up with `/whois`.
+pub struct WhoisSession {
+    pub addr: SocketAddr,
+    /// The channel or lobby for IRC, the main chat otherwise.
+    pub room: String,
+    pub connected_for: Duration,
+    pub idle_for: Duration,
+}
+
+pub fn format_whois(
+    profile: &UserProfile,
+    presence: &Presence,
+    sessions: &[WhoisSession],
+    show_private: bool,
+    settings: &UserSettings,
+    width: Option<u16>,
+) -> String {
+    let offset = settings.offset();
+    let mut table = Table::new();
+    table.add_row(row!["User", profile.username.bold()]);
+    if profile.bot {
+        table.add_row(row!["Role", format!("{} (bot)", profile.role)]);
+    } else {
+        table.add_row(row!["Role", profile.role]);
+    }
+    table.add_row(row![
+        "Registered",
+        format_date(profile.created_at.as_deref(), &offset)
+    ]);
+    table.add_row(row![
+        "Last login",
+        format_date(profile.last_login.as_deref(), &offset)
+    ]);
+
+    match sessions.first() {
+        Some(session) => {
+            table.add_row(row!["Last seen", "now"]);
+            table.add_row(row!["Status", presence.label()]);
+            if let Some(message) = presence.status.as_ref().or(presence.away_message.as_ref()) {
+                table.add_row(row!["Message", message]);
+            }
+            let mut rooms: Vec<&str> = sessions.iter().map(|s| s.room.as_str()).collect();
+            rooms.sort_unstable();
+            rooms.dedup();
+            table.add_row(row!["Room", rooms.join(", ")]);
+            table.add_row(row!["Session", format_duration(session.connected_for)]);
+            let idle = sessions
+                .iter()
+                .map(|s| s.idle_for)
+                .min()
+                .unwrap_or_default();
+            table.add_row(row!["Idle", format_duration(idle)]);
+        }
+        None => {
+            table.add_row(row![
+                "Last seen",
+                format_date(profile.last_seen.as_deref(), &offset)
+            ]);
+            table.add_row(row!["Status", "Offline".red()]);
+        }
+    }
+
+    if show_private {
+        let addresses: Vec<String> = sessions.iter().map(|s| s.addr.to_string()).collect();
+        if !addresses.is_empty() {
+            table.add_row(row!["Address", addresses.join(", ")]);
+        }
+        let sanction = match profile.role.as_str() {
+            "banned" => "banned".red(),
+            "muted" => "muted".yellow(),
+            _ => "none".normal(),
+        };
+        let sanction = if profile.locked {
+            format!("{} {}", sanction, "(locked)".red())
+        } else {
+            sanction.to_string()
+        };
+        table.add_row(row!["Sanctions", sanction]);
+    }
+
+    render_table(&mut table, width)
+}
+
+/// Largest `seen=`/`inactive=` day count accepted by `/users`.
+const MAX_FILTER_DAYS: i64 = 36500;
+/// Largest page number accepted by `/users`.
+const MAX_PAGE: i64 = 1_000_000;
+
+/// Parses the `key=value` filters of `/users` into a filter and a 1-based page.
+pub fn parse_user_filter<'a>(
+    args: impl Iterator<Item = &'a str>,
+) -> Result<(UserFilter, i64), String> {
+    let mut filter = UserFilter::default();
+    let mut page = 1;
+    for arg in args {
+        let Some((key, value)) = arg.split_once('=') else {
+            return Err(format!("Invalid filter '{}', use key=value.", arg));
+        };
+        let days = || {
+            value
+                .parse::<i64>()
+                .ok()
+                .filter(|days| (0..=MAX_FILTER_DAYS).contains(days))
+                .and_then(|days| {
+                    chrono::Utc::now().checked_sub_signed(chrono::Duration::days(days))
+                })
+                .map(|since| since.to_rfc3339())
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid number of days '{}', use 0 to {}.",
+                        value, MAX_FILTER_DAYS
+                    )
+                })
+        };
+        let date = || {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .map_err(|_| format!("Invalid date '{}', use YYYY-MM-DD.", value))
+        };
+        match key {
+            "role" => filter.role = Some(value.to_string()),
+            "seen" => filter.seen_since = Some(days()?),
+            "inactive" => filter.not_seen_since = Some(days()?),
+            "after" => filter.registered_since = Some(date()?),
+            "before" => filter.registered_before = Some(date()?),
+            "page" => {
+                page = value
+                    .parse()
+                    .ok()
+                    .filter(|page| (1..=MAX_PAGE).contains(page))
+                    .ok_or_else(|| format!("Invalid page '{}'.", value))?
+            }
+            _ => return Err(format!("Unknown filter '{}'.", key)),
+        }
+    }
+    Ok((filter, page))
+}
+
+/// Formats one page of the admin `/users` listing.
+pub fn format_users(
+    users: &[UserProfile],
+    total: i64,
+    page: i64,
+    page_size: i64,
+    settings: &UserSettings,
+    width: Option<u16>,
+) -> String {
+    let offset = settings.offset();
+    let mut table = Table::new();
+    table.add_row(row!["Username", "Role", "Registered", "Last seen", "Flags"]);
+    for user in users {
+        let mut flags = Vec::new();
+        if user.locked {
+            flags.push("locked");
+        }
+        if user.must_change_password {
+            flags.push("password reset");
+        }
+        if user.bot {
+            flags.push("bot");
+        }
+        table.add_row(row![
+            user.username,
+            user.role,
+            format_date(user.created_at.as_deref(), &offset),
+            format_date(user.last_seen.as_deref(), &offset),
+            flags.join(", ")
+        ]);
+    }
+    let pages = ((total + page_size - 1) / page_size).max(1);
+    format!(
+        "{}Page {} of {} ({} users)",
+        render_table(&mut table, width),
+        page,
+        pages,
+        total
+    )
+}
+
+/// Longest lifetime a token can be given with `expires=<days>`.
+const MAX_TOKEN_DAYS: i64 = 3650;
+
+/// Arguments of `/token create <name> [scopes=<list>] [expires=<days>] [user=<bot>]`.
+#[derive(Debug)]
+pub struct TokenRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<String>,
+    pub user: Option<String>,
+}
+
+pub fn parse_token_request<'a>(
+    mut args: impl Iterator<Item = &'a str>,
+) -> Result<TokenRequest, String> {
+    let name = args
+        .next()
+        .filter(|name| !name.contains('='))
+        .ok_or("Give the token a name.")?;
+    let mut request = TokenRequest {
+        name: name.to_string(),
+        scopes: vec![Scope::Chat, Scope::Read],
+        expires_at: None,
+        user: None,
+    };
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("scopes", value)) => {
+                request.scopes = tokens::parse_scopes(value)?;
+                if request.scopes.is_empty() {
+                    return Err("A token needs at least one scope.".to_string());
+                }
+            }
+            Some(("expires", value)) => {
+                let expires_at = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|days| (1..=MAX_TOKEN_DAYS).contains(days))
+                    .and_then(|days| {
+                        chrono::Utc::now().checked_add_signed(chrono::Duration::days(days))
+                    })
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid number of days '{}', use 1 to {}.",
+                            value, MAX_TOKEN_DAYS
+                        )
+                    })?;
+                request.expires_at = Some(expires_at.to_rfc3339());
+            }
+            Some(("user", value)) => request.user = Some(value.to_string()),
+            _ => return Err(format!("Unknown option '{}'.", arg)),
+        }
+    }
+    Ok(request)
+}
+
+/// Lists API tokens for `/token list`, without their values.
+pub fn format_tokens(tokens: &[TokenInfo], settings: &UserSettings, width: Option<u16>) -> String {
+    let offset = settings.offset();
+    let mut table = Table::new();
+    table.add_row(row![
+        "Id",
+        "User",
+        "Name",
+        "Scopes",
+        "Created",
+        "Expires",
+        "Last used"
+    ]);
+    for token in tokens {
+        table.add_row(row![
+            token.id,
+            token.username,
+            token.name,
+            tokens::format_scopes(&token.scopes),
+            format_date(Some(&token.created_at), &offset),
+            never_or_date(token.expires_at.as_deref(), &offset),
+            never_or_date(token.last_used.as_deref(), &offset)
+        ]);
+    }
+    render_table(&mut table, width)
+}
+
+/// Lists incoming webhooks for `/webhooks`, without their secrets.
+pub fn format_incoming_webhooks(
+    hooks: &[IncomingWebhook],
+    settings: &UserSettings,
+    width: Option<u16>,
+) -> String {
+    let mut table = Table::new();
+    table.add_row(row!["Id", "Posts as", "Limit", "Created by", "Created"]);
+    for hook in hooks {
+        table.add_row(row![
+            hook.id,
+            hook.name,
+            format!("{}/min", hook.rate_limit),
+            hook.created_by,
+            format_date(Some(&hook.created_at), &settings.offset())
+        ]);
+    }
+    render_table(&mut table, width)
+}
+
+/// Lists pending `/rename` requests for `/renames`.
+pub fn format_rename_requests(
+    requests: &[(String, String, String)],
+    settings: &UserSettings,
+    width: Option<u16>,
+) -> String {
+    let mut table = Table::new();
+    table.add_row(row!["Username", "New name", "Requested"]);
+    for (username, new_name, requested_at) in requests {
+        table.add_row(row![
+            username,
+            new_name.green(),
+            format_date(Some(requested_at), &settings.offset())
+        ]);
+    }
+    render_table(&mut table, width)
+}
+
+fn format_date(date: Option<&str>, offset: &FixedOffset) -> String {
+    date.and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+        .map(|date| {
+            date.with_timezone(offset)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn never_or_date(date: Option<&str>, offset: &FixedOffset) -> String {
+    match date {
+        Some(date) => format_date(Some(date), offset),
+        None => "never".to_string(),
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_filter_bounds_days_and_pages() {
+        let (filter, page) = parse_user_filter(["seen=7", "page=2"].into_iter()).unwrap();
+        assert!(filter.seen_since.is_some());
+        assert_eq!(page, 2);
+        for arg in [
+            "seen=100000000",
+            "inactive=-1",
+            "page=0",
+            "page=9223372036854775807",
+        ] {
+            assert!(parse_user_filter([arg].into_iter()).is_err(), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn whois_is_online_while_a_session_exists() {
+        let profile = UserProfile {
+            username: "alice".to_string(),
+            role: "user".to_string(),
+            created_at: None,
+            last_login: None,
+            last_seen: None,
+            must_change_password: false,
+            locked: false,
+            bot: false,
+        };
+        let settings = UserSettings::default();
+        let session = WhoisSession {
+            addr: "127.0.0.1:6667".parse().unwrap(),
+            room: "IRC lobby".to_string(),
+            connected_for: Duration::from_secs(5),
+            idle_for: Duration::from_secs(5),
+        };
+        let online = format_whois(
+            &profile,
+            &Presence::default(),
+            &[session],
+            false,
+            &settings,
+            None,
+        );
+        assert!(online.contains("Online"));
+        assert!(online.contains("IRC lobby"));
+        let offline = format_whois(&profile, &Presence::default(), &[], false, &settings, None);
+        assert!(offline.contains("Offline"));
+        assert!(!offline.contains("Room"));
+    }
+
+    #[test]
+    fn token_expiry_is_bounded() {
+        let request = parse_token_request(["ci", "expires=30"].into_iter()).unwrap();
+        assert!(request.expires_at.is_some());
+        for days in ["0", "-1", "3651", "100000000", "soon"] {
+            let arg = format!("expires={}", days);
+            assert!(parse_token_request(["ci", arg.as_str()].into_iter()).is_err());
+        }
+    }
+}
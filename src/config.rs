@@ -0,0 +1,163 @@
+use std::env;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Server options read once from `FERRUM_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Idle time after which a user is marked away; `None` disables it.
+    pub auto_away_after: Option<Duration>,
+    /// Idle time after which a connection is closed; `None` keeps it open.
+    pub idle_timeout: Option<Duration>,
+    /// Time before TCP keepalive probes start on accepted sockets.
+    pub tcp_keepalive: Option<Duration>,
+    /// Interval of WebSocket pings sent by the proxy.
+    pub websocket_ping_interval: Option<Duration>,
+    /// Whether logging in again disconnects the account's existing session.
+    pub session_takeover: bool,
+    /// Whether an account may stay logged in from several connections at once.
+    pub multiple_sessions: bool,
+    /// Failed login or registration attempts allowed per connection.
+    pub max_login_attempts: u32,
+    /// Whether new connections are offered telnet options on connect.
+    pub telnet_negotiation: bool,
+    /// Allowed length of new usernames, in characters.
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    /// Characters allowed in usernames besides letters and digits.
+    pub username_extra_chars: String,
+    /// Names nobody may register, compared case-insensitively.
+    pub reserved_usernames: Vec<String>,
+    /// Minimum length of new passwords, in characters.
+    pub password_min_length: usize,
+    /// How many of lowercase, uppercase, digits and symbols a password mixes.
+    pub password_min_classes: usize,
+    /// Whether `/rename` waits for an admin to approve it.
+    pub rename_approval: bool,
+    /// Whether deleting an account erases its messages instead of only
+    /// removing the author's name from them.
+    pub delete_account_messages: bool,
+    /// Address of the HTTP API; `None` turns it off.
+    pub api_addr: Option<SocketAddr>,
+    /// Bearer tokens accepted by the HTTP API.
+    pub api_tokens: Vec<String>,
+    /// Sender name of messages posted through the HTTP API.
+    pub api_bot_name: String,
+    /// URLs every chat event is posted to.
+    pub webhook_urls: Vec<String>,
+    /// Key of the `X-Ferrum-Signature` HMAC; unsigned when `None`.
+    pub webhook_secret: Option<String>,
+    /// Event names sent to webhooks; empty sends all of them.
+    pub webhook_events: Vec<String>,
+    /// Delivery attempts before a webhook call is given up.
+    pub webhook_max_attempts: u32,
+    /// Delay before the first retry, doubled after every failure.
+    pub webhook_retry_delay: Duration,
+    /// Default messages per minute accepted by an incoming webhook.
+    pub incoming_webhook_rate_limit: u32,
+    /// Address of the IRC gateway; `None` turns it off.
+    pub irc_addr: Option<SocketAddr>,
+    /// The one channel IRC clients join to take part in the chat.
+    pub irc_channel: String,
+    /// How long an IRC connection may stay silent before it is pinged; it is
+    /// closed after twice as long. `None` turns pings off.
+    pub irc_ping_interval: Option<Duration>,
+    /// How long the server keeps running after a shutdown signal, reporting
+    /// itself not ready, before it exits.
+    pub shutdown_grace: Duration,
+    /// Log filter used when `RUST_LOG` is not set, e.g. `info,ferrum_serve=debug`.
+    pub log_level: String,
+    /// Writes log lines as JSON objects instead of text.
+    pub log_json: bool,
+    /// Directory for rotating log files; `None` logs to stdout.
+    pub log_dir: Option<String>,
+    /// How often a new log file is started: `minutely`, `hourly`, `daily` or `never`.
+    pub log_rotation: String,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Config {
+            auto_away_after: env_duration("FERRUM_AUTO_AWAY_SECS", 600),
+            idle_timeout: env_duration("FERRUM_IDLE_TIMEOUT_SECS", 0),
+            tcp_keepalive: env_duration("FERRUM_TCP_KEEPALIVE_SECS", 60),
+            websocket_ping_interval: env_duration("FERRUM_WS_PING_SECS", 30),
+            session_takeover: env_flag("FERRUM_SESSION_TAKEOVER", true),
+            multiple_sessions: env_flag("FERRUM_MULTIPLE_SESSIONS", false),
+            max_login_attempts: env_number("FERRUM_LOGIN_ATTEMPTS", 3),
+            telnet_negotiation: env_flag("FERRUM_TELNET_NEGOTIATION", true),
+            username_min_length: env_number("FERRUM_USERNAME_MIN_LENGTH", 3),
+            username_max_length: env_number("FERRUM_USERNAME_MAX_LENGTH", 20),
+            username_extra_chars: env_string("FERRUM_USERNAME_CHARS", "_-"),
+            reserved_usernames: env_list(
+                "FERRUM_RESERVED_USERNAMES",
+                "admin,administrator,root,system,server,moderator,nobody",
+            ),
+            password_min_length: env_number("FERRUM_PASSWORD_MIN_LENGTH", 8),
+            password_min_classes: env_number("FERRUM_PASSWORD_CLASSES", 2),
+            rename_approval: env_flag("FERRUM_RENAME_APPROVAL", false),
+            delete_account_messages: env_flag("FERRUM_DELETE_ACCOUNT_MESSAGES", false),
+            api_addr: env_string("FERRUM_API_ADDR", "127.0.0.1:8082").parse().ok(),
+            api_tokens: env_list("FERRUM_API_TOKENS", ""),
+            api_bot_name: env_string("FERRUM_API_BOT_NAME", "api"),
+            webhook_urls: env_list("FERRUM_WEBHOOK_URLS", ""),
+            webhook_secret: env::var("FERRUM_WEBHOOK_SECRET")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            webhook_events: env_list("FERRUM_WEBHOOK_EVENTS", ""),
+            webhook_max_attempts: env_number("FERRUM_WEBHOOK_ATTEMPTS", 8),
+            webhook_retry_delay: Duration::from_secs(env_number("FERRUM_WEBHOOK_RETRY_SECS", 5)),
+            incoming_webhook_rate_limit: env_number("FERRUM_INCOMING_WEBHOOK_RATE", 30),
+            irc_addr: env_string("FERRUM_IRC_ADDR", "127.0.0.1:6667").parse().ok(),
+            irc_channel: env_string("FERRUM_IRC_CHANNEL", "#ferrum"),
+            irc_ping_interval: env_duration("FERRUM_IRC_PING_SECS", 60),
+            shutdown_grace: Duration::from_secs(env_number("FERRUM_SHUTDOWN_GRACE_SECS", 5)),
+            log_level: env_string("FERRUM_LOG", "info"),
+            log_json: env_string("FERRUM_LOG_FORMAT", "text").eq_ignore_ascii_case("json"),
+            log_dir: env::var("FERRUM_LOG_DIR").ok().filter(|s| !s.is_empty()),
+            log_rotation: env_string("FERRUM_LOG_ROTATION", "daily"),
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::from_env)
+}
+
+/// Reads a number of seconds, where `0` turns the feature off.
+fn env_duration(name: &str, default_secs: u64) -> Option<Duration> {
+    let secs = env_number(name, default_secs);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_string(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// Reads a comma separated list, ignoring blank entries.
+fn env_list(name: &str, default: &str) -> Vec<String> {
+    env_string(name, default)
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+    match env::var(name).as_deref() {
+        Ok("1") | Ok("true") | Ok("yes") | Ok("on") => true,
+        Ok("0") | Ok("false") | Ok("no") | Ok("off") => false,
+        _ => default,
+    }
+}
@@ -1,53 +1,498 @@
-
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::sync::Arc;
 
+use crate::settings::UserSettings;
+use crate::tokens::{self, Scope};
+use crate::validation::{self, ValidationError};
 use crate::Message;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use bcrypt::{hash, verify, BcryptError, DEFAULT_COST};
+use chrono::Utc;
 use colored::Color;
-use rusqlite::{params, Connection, Result as SqlResult};
+use rusqlite::{params, Connection, OptionalExtension, Result as SqlResult};
+use serde::Serialize;
+use std::str::FromStr;
 use tokio::sync::Mutex;
 
 pub fn init_user_database() -> SqlResult<Connection> {
-    let conn = Connection::open("db.sqlite3")?;
+    create_schema(Connection::open("db.sqlite3")?)
+}
+
+/// Creates the tables on `conn` and migrates ones from older versions.
+pub fn create_schema(mut conn: Connection) -> SqlResult<Connection> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users (
             id INTEGER PRIMARY KEY,
             username TEXT UNIQUE NOT NULL,
             password TEXT NOT NULL,
-            role TEXT NOT NULL DEFAULT 'user'
+            role TEXT NOT NULL DEFAULT 'user',
+            created_at TEXT,
+            last_login TEXT,
+            last_seen TEXT,
+            must_change_password INTEGER NOT NULL DEFAULT 0,
+            locked INTEGER NOT NULL DEFAULT 0,
+            bot INTEGER NOT NULL DEFAULT 0
         )",
         [],
     )?;
+    add_column_if_missing(&conn, "users", "created_at", "TEXT")?;
+    add_column_if_missing(&conn, "users", "last_login", "TEXT")?;
+    add_column_if_missing(&conn, "users", "last_seen", "TEXT")?;
+    add_column_if_missing(
+        &conn,
+        "users",
+        "must_change_password",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(&conn, "users", "locked", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "users", "bot", "INTEGER NOT NULL DEFAULT 0")?;
 
     conn.execute(
         "CREATE TABLE IF NOT EXISTS messages (
             id INTEGER PRIMARY KEY,
             username TEXT NOT NULL,
             message TEXT NOT NULL,
-            timestamp TEXT NOT NULL
+            timestamp TEXT NOT NULL,
+            edited INTEGER NOT NULL DEFAULT 0,
+            deleted INTEGER NOT NULL DEFAULT 0,
+            parent_id INTEGER REFERENCES messages(id)
+        )",
+        [],
+    )?;
+    add_column_if_missing(&conn, "messages", "edited", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "messages", "deleted", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(
+        &conn,
+        "messages",
+        "parent_id",
+        "INTEGER REFERENCES messages(id)",
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_edits (
+            id INTEGER PRIMARY KEY,
+            message_id INTEGER NOT NULL,
+            action TEXT NOT NULL,
+            previous TEXT NOT NULL,
+            edited_by TEXT NOT NULL,
+            edited_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_settings (
+            username TEXT PRIMARY KEY,
+            color TEXT NOT NULL DEFAULT 'green',
+            timezone TEXT NOT NULL DEFAULT 'UTC',
+            notifications INTEGER NOT NULL DEFAULT 1,
+            show_ids INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )?;
+    add_column_if_missing(
+        &conn,
+        "user_settings",
+        "show_ids",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS reactions (
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            username TEXT NOT NULL,
+            emoji TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (message_id, username, emoji)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mentions (
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            username TEXT NOT NULL,
+            seen INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (message_id, username)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rename_requests (
+            username TEXT PRIMARY KEY,
+            new_name TEXT NOT NULL,
+            requested_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            name TEXT NOT NULL,
+            token_hash TEXT UNIQUE NOT NULL,
+            scopes TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            expires_at TEXT,
+            last_used TEXT,
+            revoked INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    // Tokens are bound by name, so ones left behind by accounts deleted
+    // before they were removed with them would log in whoever registers
+    // the name next.
+    conn.execute(
+        "DELETE FROM api_tokens WHERE username NOT IN (SELECT username FROM users)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id INTEGER PRIMARY KEY,
+            url TEXT NOT NULL,
+            event TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at TEXT NOT NULL,
+            last_error TEXT,
+            delivered_at TEXT,
+            failed INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL
         )",
         [],
     )?;
 
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS incoming_webhooks (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            secret_hash TEXT UNIQUE NOT NULL,
+            rate_limit INTEGER NOT NULL,
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            revoked INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+    conn.execute(
+        "UPDATE incoming_webhooks SET revoked = 1
+         WHERE revoked = 0 AND name NOT IN (SELECT username FROM users)",
+        [],
+    )?;
+
+    conn.profile(Some(crate::metrics::record_query));
     tracing::info!("user database initialized");
     Ok(conn)
 }
 
+/// Brings databases created by older versions up to the current schema.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> SqlResult<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists([column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Why creating an account or setting a password failed. Displays as a
+/// message that can be shown to the client as is.
+#[derive(Debug)]
+pub enum AccountError {
+    Invalid(ValidationError),
+    Hash(BcryptError),
+    Database(rusqlite::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Invalid(e) => write!(f, "{}", e),
+            AccountError::Hash(_) => write!(f, "Could not store the password, please try again."),
+            AccountError::Database(_) => write!(f, "Database error, please try again later."),
+        }
+    }
+}
+
+impl Error for AccountError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AccountError::Invalid(e) => Some(e),
+            AccountError::Hash(e) => Some(e),
+            AccountError::Database(e) => Some(e),
+        }
+    }
+}
+
+impl From<ValidationError> for AccountError {
+    fn from(e: ValidationError) -> Self {
+        AccountError::Invalid(e)
+    }
+}
+
+impl From<BcryptError> for AccountError {
+    fn from(e: BcryptError) -> Self {
+        AccountError::Hash(e)
+    }
+}
+
+impl From<rusqlite::Error> for AccountError {
+    fn from(e: rusqlite::Error) -> Self {
+        AccountError::Database(e)
+    }
+}
+
 pub async fn register_user(
     conn: &Arc<Mutex<Connection>>,
     username: &str,
     password: &str,
-) -> SqlResult<()> {
-    let hashed_password = hash(password, DEFAULT_COST).unwrap();
-    conn.lock().await.execute(
-        "INSERT INTO users (username, password) VALUES (?1, ?2)",
-        [username, &hashed_password],
+) -> Result<(), AccountError> {
+    validation::validate_username(username)?;
+    validation::validate_password(username, password)?;
+    let hashed_password = hash(password, DEFAULT_COST)?;
+
+    let conn = conn.lock().await;
+    check_username_free(&conn, username, "")?;
+    conn.execute(
+        "INSERT INTO users (username, password, created_at) VALUES (?1, ?2, ?3)",
+        [username, &hashed_password, &Utc::now().to_rfc3339()],
     )?;
     tracing::info!("registered user {}", username);
     Ok(())
 }
 
+/// Fails if another account, ignoring `except`, has the name in any case.
+fn check_username_free(
+    conn: &Connection,
+    username: &str,
+    except: &str,
+) -> Result<(), AccountError> {
+    let taken = conn
+        .query_row(
+            "SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE AND username <> ?2",
+            [username, except],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if taken {
+        return Err(ValidationError::UsernameTaken(username.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Name shown for messages whose author deleted their account.
+const DELETED_USER: &str = "[deleted]";
+
+/// Removes the account and everything stored under its name, API tokens
+/// and incoming webhooks included. Messages stay so threads remain
+/// readable, attributed to [`DELETED_USER`]; with `delete_messages` their
+/// text and edit history is erased as well.
+pub async fn delete_account(
+    conn: &Mutex<Connection>,
+    username: &str,
+    delete_messages: bool,
+) -> SqlResult<()> {
+    let mut conn = conn.lock().await;
+    let tx = conn.transaction()?;
+    if delete_messages {
+        tx.execute(
+            "DELETE FROM message_edits
+             WHERE message_id IN (SELECT id FROM messages WHERE username = ?1)",
+            [username],
+        )?;
+        tx.execute(
+            "UPDATE messages SET message = '', deleted = 1 WHERE username = ?1",
+            [username],
+        )?;
+    }
+    tx.execute(
+        "UPDATE messages SET username = ?2 WHERE username = ?1",
+        [username, DELETED_USER],
+    )?;
+    tx.execute(
+        "UPDATE message_edits SET edited_by = ?2 WHERE edited_by = ?1",
+        [username, DELETED_USER],
+    )?;
+    for table in [
+        "reactions",
+        "mentions",
+        "user_settings",
+        "rename_requests",
+        "api_tokens",
+        "users",
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE username = ?1", table),
+            [username],
+        )?;
+    }
+    tx.execute(
+        "UPDATE incoming_webhooks SET revoked = 1 WHERE name = ?1",
+        [username],
+    )?;
+    tx.commit()?;
+    tracing::info!("deleted account {}", username);
+    Ok(())
+}
+
+/// Renames an account and moves its messages, reactions, settings, API
+/// tokens and incoming webhooks over.
+pub async fn rename_user(
+    conn: &Mutex<Connection>,
+    username: &str,
+    new_name: &str,
+) -> Result<(), AccountError> {
+    validation::validate_username(new_name)?;
+    let mut conn = conn.lock().await;
+    check_username_free(&conn, new_name, username)?;
+    let tx = conn.transaction()?;
+    for table in [
+        "users",
+        "messages",
+        "user_settings",
+        "reactions",
+        "mentions",
+        "api_tokens",
+    ] {
+        tx.execute(
+            &format!("UPDATE {} SET username = ?2 WHERE username = ?1", table),
+            [username, new_name],
+        )?;
+    }
+    tx.execute(
+        "UPDATE message_edits SET edited_by = ?2 WHERE edited_by = ?1",
+        [username, new_name],
+    )?;
+    tx.execute(
+        "UPDATE incoming_webhooks SET name = ?2 WHERE name = ?1",
+        [username, new_name],
+    )?;
+    tx.execute(
+        "DELETE FROM rename_requests WHERE username = ?1",
+        [username],
+    )?;
+    tx.commit()?;
+    tracing::info!("renamed user {} to {}", username, new_name);
+    Ok(())
+}
+
+/// Queues a rename for admin approval, replacing an earlier request.
+pub async fn request_rename(
+    conn: &Mutex<Connection>,
+    username: &str,
+    new_name: &str,
+) -> Result<(), AccountError> {
+    validation::validate_username(new_name)?;
+    let conn = conn.lock().await;
+    check_username_free(&conn, new_name, username)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO rename_requests (username, new_name, requested_at)
+         VALUES (?1, ?2, ?3)",
+        [username, new_name, &Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Pending renames as `(username, new_name, requested_at)`, oldest first.
+pub async fn get_rename_requests(
+    conn: &Mutex<Connection>,
+) -> SqlResult<Vec<(String, String, String)>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(
+        "SELECT username, new_name, requested_at FROM rename_requests ORDER BY requested_at",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    rows.collect()
+}
+
+/// Removes a pending rename and returns the requested name.
+pub async fn take_rename_request(
+    conn: &Mutex<Connection>,
+    username: &str,
+) -> SqlResult<Option<String>> {
+    let conn = conn.lock().await;
+    let new_name = conn
+        .query_row(
+            "SELECT new_name FROM rename_requests WHERE username = ?1",
+            [username],
+            |row| row.get(0),
+        )
+        .optional()?;
+    conn.execute(
+        "DELETE FROM rename_requests WHERE username = ?1",
+        [username],
+    )?;
+    Ok(new_name)
+}
+
+/// Everything stored about an account, as returned by `/exportme`.
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    pub exported_at: String,
+    pub account: UserProfile,
+    pub settings: UserSettings,
+    pub messages: Vec<Message>,
+    pub reactions: Vec<ExportedReaction>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedReaction {
+    pub message_id: i64,
+    pub emoji: String,
+    pub created_at: String,
+}
+
+pub async fn export_account(
+    conn: &Mutex<Connection>,
+    username: &str,
+) -> SqlResult<Option<AccountExport>> {
+    let Some(account) = get_user_profile(conn, username).await? else {
+        return Ok(None);
+    };
+    let settings = get_user_settings(conn, username).await?;
+    let messages = get_messages_by_user(conn, username).await?;
+    let reactions = {
+        let conn = conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT message_id, emoji, created_at FROM reactions
+             WHERE username = ?1 ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map([username], |row| {
+            Ok(ExportedReaction {
+                message_id: row.get(0)?,
+                emoji: row.get(1)?,
+                created_at: row.get(2)?,
+            })
+        })?;
+        rows.collect::<SqlResult<Vec<_>>>()?
+    };
+    Ok(Some(AccountExport {
+        exported_at: Utc::now().to_rfc3339(),
+        account,
+        settings,
+        messages,
+        reactions,
+    }))
+}
+
 pub async fn authenticate_user(
     conn: &Arc<Mutex<Connection>>,
     username: &str,
@@ -60,20 +505,373 @@ pub async fn authenticate_user(
         Err(_) => return Ok(false),
     };
 
-    let is_valid = verify(password, &stored_password).unwrap();
+    let is_valid = match verify(password, &stored_password) {
+        Ok(is_valid) => is_valid,
+        Err(e) => {
+            tracing::error!("stored password hash of {} is invalid: {:?}", username, e);
+            false
+        }
+    };
     tracing::info!("user {} authenticated: {}", username, is_valid);
 
     Ok(is_valid)
 }
 
-pub async fn store_message(conn: &Arc<Mutex<Connection>>, message: &Message) -> SqlResult<()> {
-    conn.lock().await.execute(
-        "INSERT INTO messages (username, message, timestamp) VALUES (?1, ?2, ?3)",
-        params![message.sender, message.content, message.timestamp],
+/// Runs a trivial query to check the connection is usable.
+pub async fn ping(conn: &Mutex<Connection>) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    conn.query_row("SELECT 1", [], |_| Ok(()))
+}
+
+pub async fn record_login(conn: &Mutex<Connection>, username: &str) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    let now = Utc::now().to_rfc3339();
+    conn.execute(
+        "UPDATE users SET last_login = ?1, last_seen = ?1 WHERE username = ?2",
+        params![now, username],
+    )?;
+    Ok(())
+}
+
+pub async fn record_logout(conn: &Mutex<Connection>, username: &str) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    conn.execute(
+        "UPDATE users SET last_seen = ?1 WHERE username = ?2",
+        params![Utc::now().to_rfc3339(), username],
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserProfile {
+    pub username: String,
+    pub role: String,
+    pub created_at: Option<String>,
+    pub last_login: Option<String>,
+    pub last_seen: Option<String>,
+    pub must_change_password: bool,
+    pub locked: bool,
+    pub bot: bool,
+}
+
+const PROFILE_COLUMNS: &str =
+    "username, role, created_at, last_login, last_seen, must_change_password, locked, bot";
+
+fn profile_from_row(row: &rusqlite::Row) -> SqlResult<UserProfile> {
+    Ok(UserProfile {
+        username: row.get(0)?,
+        role: row.get(1)?,
+        created_at: row.get(2)?,
+        last_login: row.get(3)?,
+        last_seen: row.get(4)?,
+        must_change_password: row.get(5)?,
+        locked: row.get(6)?,
+        bot: row.get(7)?,
+    })
+}
+
+pub async fn get_user_profile(
+    conn: &Mutex<Connection>,
+    username: &str,
+) -> SqlResult<Option<UserProfile>> {
+    let conn = conn.lock().await;
+    conn.query_row(
+        &format!("SELECT {} FROM users WHERE username = ?1", PROFILE_COLUMNS),
+        params![username],
+        profile_from_row,
+    )
+    .optional()
+}
+
+/// Filters for the admin `/users` listing. Dates are RFC 3339 strings, which
+/// compare correctly as text.
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    pub role: Option<String>,
+    pub seen_since: Option<String>,
+    pub not_seen_since: Option<String>,
+    pub registered_since: Option<String>,
+    pub registered_before: Option<String>,
+}
+
+/// Returns one page of accounts matching `filter`, sorted by name, and the
+/// number of matching accounts in total.
+pub async fn find_users(
+    conn: &Mutex<Connection>,
+    filter: &UserFilter,
+    limit: i64,
+    offset: i64,
+) -> SqlResult<(Vec<UserProfile>, i64)> {
+    let conn = conn.lock().await;
+    let condition = "(?1 IS NULL OR role = ?1)
+         AND (?2 IS NULL OR last_seen >= ?2)
+         AND (?3 IS NULL OR last_seen IS NULL OR last_seen < ?3)
+         AND (?4 IS NULL OR created_at >= ?4)
+         AND (?5 IS NULL OR created_at < ?5)";
+    let filter_params = params![
+        filter.role,
+        filter.seen_since,
+        filter.not_seen_since,
+        filter.registered_since,
+        filter.registered_before,
+    ];
+    let total = conn.query_row(
+        &format!("SELECT COUNT(*) FROM users WHERE {}", condition),
+        filter_params,
+        |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users WHERE {} ORDER BY username COLLATE NOCASE LIMIT ?6 OFFSET ?7",
+        PROFILE_COLUMNS, condition
+    ))?;
+    let rows = stmt.query_map(
+        params![
+            filter.role,
+            filter.seen_since,
+            filter.not_seen_since,
+            filter.registered_since,
+            filter.registered_before,
+            limit,
+            offset,
+        ],
+        profile_from_row,
+    )?;
+    let users = rows.collect::<SqlResult<Vec<_>>>()?;
+    Ok((users, total))
+}
+
+/// Replaces the password with a temporary one that has to be changed at the
+/// next login. Skips the password policy, the server picks the password.
+pub async fn set_temporary_password(
+    conn: &Mutex<Connection>,
+    username: &str,
+    password: &str,
+) -> Result<bool, AccountError> {
+    let hashed_password = hash(password, DEFAULT_COST)?;
+    let conn = conn.lock().await;
+    let updated = conn.execute(
+        "UPDATE users SET password = ?1, must_change_password = 1 WHERE username = ?2",
+        params![hashed_password, username],
     )?;
+    Ok(updated > 0)
+}
+
+/// Creates a bot account. Bots get a random password nobody knows, so they
+/// can only log in with API tokens.
+pub async fn create_bot(conn: &Mutex<Connection>, name: &str) -> Result<(), AccountError> {
+    validation::validate_username(name)?;
+    let conn = conn.lock().await;
+    check_username_free(&conn, name, "")?;
+    insert_bot(&conn, name)
+}
+
+/// Creates the bot that configured API tokens post as, unless it exists.
+/// Its name is reserved, so it skips the username checks.
+pub async fn ensure_api_bot(conn: &Mutex<Connection>, name: &str) -> Result<(), AccountError> {
+    let conn = conn.lock().await;
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM users WHERE username = ?1 COLLATE NOCASE",
+            [name],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if exists {
+        return Ok(());
+    }
+    insert_bot(&conn, name)
+}
+
+fn insert_bot(conn: &Connection, name: &str) -> Result<(), AccountError> {
+    let hashed_password = hash(tokens::generate_token(), DEFAULT_COST)?;
+    conn.execute(
+        "INSERT INTO users (username, password, created_at, bot) VALUES (?1, ?2, ?3, 1)",
+        [name, &hashed_password, &Utc::now().to_rfc3339()],
+    )?;
+    tracing::info!("created bot account {}", name);
     Ok(())
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenInfo {
+    pub id: i64,
+    pub username: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used: Option<String>,
+}
+
+const TOKEN_COLUMNS: &str = "id, username, name, scopes, created_at, expires_at, last_used";
+
+fn token_from_row(row: &rusqlite::Row) -> SqlResult<TokenInfo> {
+    let scopes: String = row.get(3)?;
+    Ok(TokenInfo {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        name: row.get(2)?,
+        scopes: tokens::parse_scopes(&scopes).unwrap_or_default(),
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        last_used: row.get(6)?,
+    })
+}
+
+/// Creates an API token and returns its id and value. The value is not
+/// stored and cannot be shown again.
+pub async fn create_token(
+    conn: &Mutex<Connection>,
+    username: &str,
+    name: &str,
+    scopes: &[Scope],
+    expires_at: Option<String>,
+) -> SqlResult<(i64, String)> {
+    let token = tokens::generate_token();
+    let conn = conn.lock().await;
+    conn.execute(
+        "INSERT INTO api_tokens (username, name, token_hash, scopes, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            username,
+            name,
+            tokens::hash_token(&token),
+            tokens::format_scopes(scopes),
+            Utc::now().to_rfc3339(),
+            expires_at,
+        ],
+    )?;
+    Ok((conn.last_insert_rowid(), token))
+}
+
+/// Lists tokens that have not been revoked, of one user or of everyone.
+pub async fn get_tokens(
+    conn: &Mutex<Connection>,
+    username: Option<&str>,
+) -> SqlResult<Vec<TokenInfo>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM api_tokens WHERE revoked = 0 AND (?1 IS NULL OR username = ?1)
+         ORDER BY id",
+        TOKEN_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![username], token_from_row)?;
+    rows.collect()
+}
+
+/// Revokes a token, only if it belongs to `owner` when one is given.
+pub async fn revoke_token(
+    conn: &Mutex<Connection>,
+    id: i64,
+    owner: Option<&str>,
+) -> SqlResult<bool> {
+    let conn = conn.lock().await;
+    let updated = conn.execute(
+        "UPDATE api_tokens SET revoked = 1
+         WHERE id = ?1 AND revoked = 0 AND (?2 IS NULL OR username = ?2)",
+        params![id, owner],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Looks up a token that is neither revoked nor expired and records its use.
+pub async fn authenticate_token(
+    conn: &Mutex<Connection>,
+    token: &str,
+) -> SqlResult<Option<TokenInfo>> {
+    let conn = conn.lock().await;
+    let now = Utc::now().to_rfc3339();
+    let info = conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM api_tokens
+                 WHERE token_hash = ?1 AND revoked = 0 AND (expires_at IS NULL OR expires_at > ?2)",
+                TOKEN_COLUMNS
+            ),
+            params![tokens::hash_token(token), now],
+            token_from_row,
+        )
+        .optional()?;
+    if let Some(info) = &info {
+        conn.execute(
+            "UPDATE api_tokens SET last_used = ?1 WHERE id = ?2",
+            params![now, info.id],
+        )?;
+    }
+    Ok(info)
+}
+
+/// Locks or unlocks an account, returning `false` if it does not exist.
+pub async fn set_locked(conn: &Mutex<Connection>, username: &str, locked: bool) -> SqlResult<bool> {
+    let conn = conn.lock().await;
+    let updated = conn.execute(
+        "UPDATE users SET locked = ?1 WHERE username = ?2",
+        params![locked, username],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Inserts the message and assigns it the id of the new row.
+pub async fn store_message(conn: &Arc<Mutex<Connection>>, message: &mut Message) -> SqlResult<i64> {
+    let conn = conn.lock().await;
+    conn.execute(
+        "INSERT INTO messages (username, message, timestamp, parent_id) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            message.sender,
+            message.content,
+            message.timestamp,
+            message.parent_id
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+    message.id = Some(id);
+    Ok(id)
+}
+
+/// Returns the sender of a message that has not been deleted.
+pub async fn get_message_owner(conn: &Mutex<Connection>, id: i64) -> SqlResult<Option<String>> {
+    let conn = conn.lock().await;
+    conn.query_row(
+        "SELECT username FROM messages WHERE id = ?1 AND deleted = 0",
+        params![id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+pub async fn edit_message(
+    conn: &Mutex<Connection>,
+    id: i64,
+    editor: &str,
+    content: &str,
+) -> SqlResult<()> {
+    let mut conn = conn.lock().await;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO message_edits (message_id, action, previous, edited_by, edited_at)
+         SELECT id, 'edit', message, ?2, ?3 FROM messages WHERE id = ?1",
+        params![id, editor, Utc::now().to_rfc3339()],
+    )?;
+    tx.execute(
+        "UPDATE messages SET message = ?2, edited = 1 WHERE id = ?1",
+        params![id, content],
+    )?;
+    tx.commit()
+}
+
+pub async fn delete_message(conn: &Mutex<Connection>, id: i64, editor: &str) -> SqlResult<()> {
+    let mut conn = conn.lock().await;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO message_edits (message_id, action, previous, edited_by, edited_at)
+         SELECT id, 'delete', message, ?2, ?3 FROM messages WHERE id = ?1",
+        params![id, editor, Utc::now().to_rfc3339()],
+    )?;
+    tx.execute("UPDATE messages SET deleted = 1 WHERE id = ?1", params![id])?;
+    tx.commit()
+}
+
 pub async fn get_all_users(conn: &Mutex<Connection>) -> Result<Vec<String>, rusqlite::Error> {
     let conn = conn.lock().await;
     let mut stmt = conn.prepare("SELECT username FROM users")?;
@@ -85,12 +883,39 @@ pub async fn get_all_users(conn: &Mutex<Connection>) -> Result<Vec<String>, rusq
     Ok(users)
 }
 
-pub async fn get_all_messages(conn: &Mutex<Connection>) -> Result<Vec<String>, rusqlite::Error> {
+const MESSAGE_COLUMNS: &str = "id, username, message, timestamp, edited, parent_id";
+
+fn message_from_row(row: &rusqlite::Row) -> SqlResult<Message> {
+    Ok(Message::from_database(
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    ))
+}
+
+pub async fn get_message(conn: &Mutex<Connection>, id: i64) -> SqlResult<Option<Message>> {
     let conn = conn.lock().await;
-    let mut stmt = conn.prepare("SELECT username, message, timestamp FROM messages")?;
-    let rows = stmt.query_map([], |row| {
-        Ok(Message::from_database(row.get(0)?, row.get(1)?, row.get(2)?).format(Color::Blue))
-    })?;
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM messages WHERE id = ?1 AND deleted = 0",
+            MESSAGE_COLUMNS
+        ),
+        params![id],
+        message_from_row,
+    )
+    .optional()
+}
+
+pub async fn get_all_messages(conn: &Mutex<Connection>) -> Result<Vec<Message>, rusqlite::Error> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE deleted = 0",
+        MESSAGE_COLUMNS
+    ))?;
+    let rows = stmt.query_map([], message_from_row)?;
     let mut messages = Vec::new();
     for message in rows {
         messages.push(message?);
@@ -98,18 +923,123 @@ pub async fn get_all_messages(conn: &Mutex<Connection>) -> Result<Vec<String>, r
     Ok(messages)
 }
 
+/// Returns up to `limit` messages older than `before`, oldest first, for
+/// paging backwards through the history.
+pub async fn get_messages_page(
+    conn: &Mutex<Connection>,
+    before: Option<i64>,
+    limit: i64,
+) -> SqlResult<Vec<Message>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE deleted = 0 AND (?1 IS NULL OR id < ?1)
+         ORDER BY id DESC LIMIT ?2",
+        MESSAGE_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![before, limit], message_from_row)?;
+    let mut messages = rows.collect::<SqlResult<Vec<_>>>()?;
+    messages.reverse();
+    Ok(messages)
+}
+
+/// The newest `limit` messages after `after`, oldest first, for clients
+/// catching up on what they missed.
+pub async fn get_messages_after(
+    conn: &Mutex<Connection>,
+    after: i64,
+    limit: i64,
+) -> SqlResult<Vec<Message>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE deleted = 0 AND id > ?1 ORDER BY id DESC LIMIT ?2",
+        MESSAGE_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![after, limit], message_from_row)?;
+    let mut messages = rows.collect::<SqlResult<Vec<_>>>()?;
+    messages.reverse();
+    Ok(messages)
+}
+
 pub async fn get_messages_by_user(
     conn: &Mutex<Connection>,
     username: &str,
-) -> Result<Vec<String>, rusqlite::Error> {
+) -> Result<Vec<Message>, rusqlite::Error> {
     let conn = conn.lock().await;
-    let mut stmt = conn.prepare("SELECT message, timestamp FROM messages WHERE username = ?1")?;
-    let rows = stmt.query_map([username], |row| {
-        Ok(
-            Message::from_database(username.to_string(), row.get(0)?, row.get(1)?)
-                .format(Color::Blue),
-        )
-    })?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE username = ?1 AND deleted = 0",
+        MESSAGE_COLUMNS
+    ))?;
+    let rows = stmt.query_map([username], message_from_row)?;
+    let mut messages = Vec::new();
+    for message in rows {
+        messages.push(message?);
+    }
+    Ok(messages)
+}
+
+/// Returns every message in the thread containing `id`, starting from its root.
+/// Deleted messages are kept with their content blanked so replies stay attached.
+pub async fn get_thread(conn: &Mutex<Connection>, id: i64) -> SqlResult<Vec<Message>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(
+        "WITH RECURSIVE
+            ancestors(id, parent_id) AS (
+                SELECT id, parent_id FROM messages WHERE id = ?1
+                UNION ALL
+                SELECT m.id, m.parent_id FROM messages m JOIN ancestors a ON m.id = a.parent_id
+            ),
+            thread(id) AS (
+                SELECT id FROM ancestors WHERE parent_id IS NULL
+                UNION ALL
+                SELECT m.id FROM messages m JOIN thread t ON m.parent_id = t.id
+            )
+         SELECT m.id, m.username,
+                CASE WHEN m.deleted = 1 THEN '[deleted]' ELSE m.message END,
+                m.timestamp, m.edited, m.parent_id
+         FROM thread t JOIN messages m ON m.id = t.id
+         ORDER BY m.id",
+    )?;
+    let rows = stmt.query_map(params![id], message_from_row)?;
+    let mut messages = Vec::new();
+    for message in rows {
+        messages.push(message?);
+    }
+    Ok(messages)
+}
+
+/// Records which registered users a message mentions; unknown names are ignored.
+pub async fn store_mentions(
+    conn: &Mutex<Connection>,
+    message_id: i64,
+    usernames: &[String],
+) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    for username in usernames {
+        conn.execute(
+            "INSERT OR IGNORE INTO mentions (message_id, username)
+             SELECT ?1, username FROM users WHERE username = ?2",
+            params![message_id, username],
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the latest messages mentioning `username`, oldest first.
+pub async fn get_mentions(
+    conn: &Mutex<Connection>,
+    username: &str,
+    limit: u32,
+) -> SqlResult<Vec<Message>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(
+        "SELECT * FROM (
+            SELECT m.id, m.username, m.message, m.timestamp, m.edited, m.parent_id
+            FROM mentions n JOIN messages m ON m.id = n.message_id
+            WHERE n.username = ?1 AND m.deleted = 0
+            ORDER BY m.id DESC LIMIT ?2
+         ) ORDER BY id",
+    )?;
+    let rows = stmt.query_map(params![username, limit], message_from_row)?;
     let mut messages = Vec::new();
     for message in rows {
         messages.push(message?);
@@ -117,15 +1047,104 @@ pub async fn get_messages_by_user(
     Ok(messages)
 }
 
+pub async fn count_unseen_mentions(conn: &Mutex<Connection>, username: &str) -> SqlResult<i64> {
+    let conn = conn.lock().await;
+    conn.query_row(
+        "SELECT COUNT(*) FROM mentions n JOIN messages m ON m.id = n.message_id
+         WHERE n.username = ?1 AND n.seen = 0 AND m.deleted = 0",
+        params![username],
+        |row| row.get(0),
+    )
+}
+
+/// Marks one mention as seen, or all of the user's mentions when `message_id` is `None`.
+pub async fn mark_mentions_seen(
+    conn: &Mutex<Connection>,
+    username: &str,
+    message_id: Option<i64>,
+) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    conn.execute(
+        "UPDATE mentions SET seen = 1 WHERE username = ?1 AND (?2 IS NULL OR message_id = ?2)",
+        params![username, message_id],
+    )?;
+    Ok(())
+}
+
+/// Adds the reaction, or removes it if the user already reacted with that emoji.
+/// Returns whether the reaction is now present.
+pub async fn toggle_reaction(
+    conn: &Mutex<Connection>,
+    message_id: i64,
+    username: &str,
+    emoji: &str,
+) -> SqlResult<bool> {
+    let conn = conn.lock().await;
+    let removed = conn.execute(
+        "DELETE FROM reactions WHERE message_id = ?1 AND username = ?2 AND emoji = ?3",
+        params![message_id, username, emoji],
+    )?;
+    if removed > 0 {
+        return Ok(false);
+    }
+    conn.execute(
+        "INSERT INTO reactions (message_id, username, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![message_id, username, emoji, Utc::now().to_rfc3339()],
+    )?;
+    Ok(true)
+}
+
+/// Returns the reaction counts of every message that has any, in the order
+/// each emoji was first used on that message.
+pub async fn get_reaction_counts(
+    conn: &Mutex<Connection>,
+) -> Result<HashMap<i64, Vec<(String, i64)>>, rusqlite::Error> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(
+        "SELECT message_id, emoji, COUNT(*) FROM reactions
+         GROUP BY message_id, emoji
+         ORDER BY message_id, MIN(created_at)",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut reactions: HashMap<i64, Vec<(String, i64)>> = HashMap::new();
+    for row in rows {
+        let (message_id, emoji, count) = row?;
+        reactions
+            .entry(message_id)
+            .or_default()
+            .push((emoji, count));
+    }
+    Ok(reactions)
+}
+
+/// Returns the stored name color of every user who has saved one.
+pub async fn get_user_colors(
+    conn: &Mutex<Connection>,
+) -> Result<HashMap<String, Color>, rusqlite::Error> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare("SELECT username, color FROM user_settings")?;
+    let rows = stmt.query_map([], |row| {
+        let color: String = row.get(1)?;
+        Ok((row.get(0)?, Color::from_str(&color).unwrap_or(Color::Green)))
+    })?;
+    let mut colors = HashMap::new();
+    for row in rows {
+        let (username, color) = row?;
+        colors.insert(username, color);
+    }
+    Ok(colors)
+}
+
 pub async fn update_user_password(
     conn: &Mutex<Connection>,
     username: &str,
     new_password: &str,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), AccountError> {
+    validation::validate_password(username, new_password)?;
+    let hashed_password = hash(new_password, DEFAULT_COST)?;
     let conn = conn.lock().await;
-    let hashed_password = hash(new_password, DEFAULT_COST).unwrap();
     conn.execute(
-        "UPDATE users SET password = ?1 WHERE username = ?2",
+        "UPDATE users SET password = ?1, must_change_password = 0 WHERE username = ?2",
         params![hashed_password, username],
     )?;
 
@@ -142,9 +1161,232 @@ pub async fn change_role(conn: &Mutex<Connection>, username: &str, role: &str) -
     Ok(())
 }
 
-pub async fn get_user_role(conn: &Mutex<Connection>, username: &str) -> Result<String, rusqlite::Error> {
+pub async fn get_user_role(
+    conn: &Mutex<Connection>,
+    username: &str,
+) -> Result<String, rusqlite::Error> {
     let conn = conn.lock().await;
     let mut stmt = conn.prepare("SELECT role FROM users WHERE username = ?1")?;
     let role: String = stmt.query_row(params![username], |row| row.get(0))?;
     Ok(role)
-}
\ No newline at end of file
+}
+
+pub async fn get_user_settings(
+    conn: &Mutex<Connection>,
+    username: &str,
+) -> Result<UserSettings, rusqlite::Error> {
+    let conn = conn.lock().await;
+    let settings = conn
+        .query_row(
+            "SELECT color, timezone, notifications, show_ids FROM user_settings
+             WHERE username = ?1",
+            params![username],
+            |row| {
+                Ok(UserSettings {
+                    color: row.get(0)?,
+                    timezone: row.get(1)?,
+                    notifications: row.get(2)?,
+                    show_ids: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(settings.unwrap_or_default())
+}
+
+pub async fn save_user_settings(
+    conn: &Mutex<Connection>,
+    username: &str,
+    settings: &UserSettings,
+) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    conn.execute(
+        "INSERT OR REPLACE INTO user_settings (username, color, timezone, notifications, show_ids)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            username,
+            settings.color,
+            settings.timezone,
+            settings.notifications,
+            settings.show_ids
+        ],
+    )?;
+    Ok(())
+}
+
+/// A queued webhook call.
+#[derive(Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: u32,
+}
+
+pub async fn enqueue_webhook(
+    conn: &Mutex<Connection>,
+    url: &str,
+    event: &str,
+    payload: &str,
+) -> SqlResult<i64> {
+    let conn = conn.lock().await;
+    let now = Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO webhook_deliveries (url, event, payload, next_attempt_at, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?4)",
+        params![url, event, payload, now],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Returns pending deliveries whose next attempt is due, oldest first.
+pub async fn get_due_webhooks(
+    conn: &Mutex<Connection>,
+    limit: i64,
+) -> SqlResult<Vec<WebhookDelivery>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(
+        "SELECT id, url, event, payload, attempts FROM webhook_deliveries
+         WHERE delivered_at IS NULL AND failed = 0 AND next_attempt_at <= ?1
+         ORDER BY id LIMIT ?2",
+    )?;
+    let rows = stmt.query_map(params![Utc::now().to_rfc3339(), limit], |row| {
+        Ok(WebhookDelivery {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            event: row.get(2)?,
+            payload: row.get(3)?,
+            attempts: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub async fn mark_webhook_delivered(conn: &Mutex<Connection>, id: i64) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    conn.execute(
+        "UPDATE webhook_deliveries SET attempts = attempts + 1, delivered_at = ?1, last_error = NULL
+         WHERE id = ?2",
+        params![Utc::now().to_rfc3339(), id],
+    )?;
+    Ok(())
+}
+
+/// Records a failed attempt. Without a `retry_at` the delivery is given up.
+pub async fn mark_webhook_failed(
+    conn: &Mutex<Connection>,
+    id: i64,
+    error: &str,
+    retry_at: Option<String>,
+) -> SqlResult<()> {
+    let conn = conn.lock().await;
+    conn.execute(
+        "UPDATE webhook_deliveries
+         SET attempts = attempts + 1, last_error = ?1,
+             next_attempt_at = COALESCE(?2, next_attempt_at), failed = ?2 IS NULL
+         WHERE id = ?3",
+        params![error, retry_at, id],
+    )?;
+    Ok(())
+}
+
+/// A secret URL that posts into the chat as the bot account `name`.
+#[derive(Debug, Clone)]
+pub struct IncomingWebhook {
+    pub id: i64,
+    pub name: String,
+    /// Messages accepted per minute.
+    pub rate_limit: u32,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+const INCOMING_WEBHOOK_COLUMNS: &str = "id, name, rate_limit, created_by, created_at";
+
+fn incoming_webhook_from_row(row: &rusqlite::Row) -> SqlResult<IncomingWebhook> {
+    Ok(IncomingWebhook {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        rate_limit: row.get(2)?,
+        created_by: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+/// Creates an incoming webhook and returns its id and secret. Like API
+/// tokens, only a hash of the secret is stored.
+pub async fn create_incoming_webhook(
+    conn: &Mutex<Connection>,
+    name: &str,
+    rate_limit: u32,
+    created_by: &str,
+) -> SqlResult<(i64, String)> {
+    let secret = tokens::generate_token();
+    let conn = conn.lock().await;
+    conn.execute(
+        "INSERT INTO incoming_webhooks (name, secret_hash, rate_limit, created_by, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            name,
+            tokens::hash_token(&secret),
+            rate_limit,
+            created_by,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    Ok((conn.last_insert_rowid(), secret))
+}
+
+pub async fn get_incoming_webhooks(conn: &Mutex<Connection>) -> SqlResult<Vec<IncomingWebhook>> {
+    let conn = conn.lock().await;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM incoming_webhooks WHERE revoked = 0 ORDER BY id",
+        INCOMING_WEBHOOK_COLUMNS
+    ))?;
+    let rows = stmt.query_map([], incoming_webhook_from_row)?;
+    rows.collect()
+}
+
+pub async fn find_incoming_webhook(
+    conn: &Mutex<Connection>,
+    secret: &str,
+) -> SqlResult<Option<IncomingWebhook>> {
+    let conn = conn.lock().await;
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM incoming_webhooks WHERE secret_hash = ?1 AND revoked = 0",
+            INCOMING_WEBHOOK_COLUMNS
+        ),
+        [tokens::hash_token(secret)],
+        incoming_webhook_from_row,
+    )
+    .optional()
+}
+
+pub async fn revoke_incoming_webhook(conn: &Mutex<Connection>, id: i64) -> SqlResult<bool> {
+    let conn = conn.lock().await;
+    let updated = conn.execute(
+        "UPDATE incoming_webhooks SET revoked = 1 WHERE id = ?1 AND revoked = 0",
+        [id],
+    )?;
+    Ok(updated > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn malformed_password_hash_fails_authentication() {
+        let conn = create_schema(Connection::open_in_memory().unwrap()).unwrap();
+        conn.execute(
+            "INSERT INTO users (username, password) VALUES ('alice', 'not a hash')",
+            [],
+        )
+        .unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        assert!(!authenticate_user(&conn, "alice", "password").await.unwrap());
+        assert!(!authenticate_user(&conn, "bob", "password").await.unwrap());
+    }
+}
@@ -0,0 +1,71 @@
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("heart", "❤️"),
+    ("smile", "😄"),
+    ("grin", "😁"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("wink", "😉"),
+    ("thinking", "🤔"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("angry", "😠"),
+    ("open_mouth", "😮"),
+    ("eyes", "👀"),
+    ("clap", "👏"),
+    ("pray", "🙏"),
+    ("wave", "👋"),
+    ("ok_hand", "👌"),
+    ("muscle", "💪"),
+    ("fire", "🔥"),
+    ("tada", "🎉"),
+    ("rocket", "🚀"),
+    ("star", "⭐"),
+    ("100", "💯"),
+    ("check", "✅"),
+    ("white_check_mark", "✅"),
+    ("x", "❌"),
+    ("warning", "⚠️"),
+    ("bug", "🐛"),
+    ("crab", "🦀"),
+    ("coffee", "☕"),
+    ("beer", "🍺"),
+    ("pizza", "🍕"),
+    ("sparkles", "✨"),
+    ("zap", "⚡"),
+    ("question", "❓"),
+];
+
+pub fn lookup(shortcode: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(name, _)| *name == shortcode)
+        .map(|(_, emoji)| *emoji)
+}
+
+/// Turns a reaction argument into the emoji to store: `:shortcode:` is expanded,
+/// anything else must already be a short non-alphanumeric symbol.
+pub fn parse_reaction(input: &str) -> Option<String> {
+    if let Some(shortcode) = input
+        .strip_prefix(':')
+        .and_then(|rest| rest.strip_suffix(':'))
+    {
+        return lookup(shortcode).map(str::to_string);
+    }
+    let is_symbol = input
+        .chars()
+        .all(|c| !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace());
+    if !input.is_empty() && input.chars().count() <= 8 && is_symbol {
+        Some(input.to_string())
+    } else {
+        None
+    }
+}
+
+/// Lists the shortcodes accepted by `/react`.
+pub fn shortcodes() -> impl Iterator<Item = (&'static str, &'static str)> {
+    SHORTCODES.iter().copied()
+}
@@ -0,0 +1,44 @@
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::message::Message;
+
+/// Something that happened in the chat, published to webhooks and other
+/// subscribers through [`crate::Shared::publish`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChatEvent {
+    MessagePosted { message: Message },
+    UserJoined { username: String },
+    UserLeft { username: String },
+    UserBanned { username: String, by: String },
+}
+
+impl ChatEvent {
+    pub const NAMES: [&'static str; 4] =
+        ["message_posted", "user_joined", "user_left", "user_banned"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatEvent::MessagePosted { .. } => "message_posted",
+            ChatEvent::UserJoined { .. } => "user_joined",
+            ChatEvent::UserLeft { .. } => "user_left",
+            ChatEvent::UserBanned { .. } => "user_banned",
+        }
+    }
+
+    /// The id of the posted message, for clients resuming a stream.
+    pub fn message_id(&self) -> Option<i64> {
+        match self {
+            ChatEvent::MessagePosted { message } => message.id,
+            _ => None,
+        }
+    }
+
+    /// The JSON sent to subscribers, with the time it was serialized.
+    pub fn to_json(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        value["timestamp"] = Utc::now().to_rfc3339().into();
+        value.to_string()
+    }
+}
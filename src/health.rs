@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio::sync::{watch, Mutex};
+
+use crate::{config, database};
+
+/// Longest wait for the database before it counts as unusable.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The accept loops whose state is reported by the health checks.
+#[derive(Debug, Clone, Copy)]
+pub enum Listener {
+    Tcp,
+    WebSocket,
+    Irc,
+}
+
+static TCP: AtomicBool = AtomicBool::new(false);
+static WEBSOCKET: AtomicBool = AtomicBool::new(false);
+static IRC: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn shutdown_sender() -> &'static watch::Sender<bool> {
+    SHUTDOWN.get_or_init(|| watch::channel(false).0)
+}
+
+impl Listener {
+    fn flag(self) -> &'static AtomicBool {
+        match self {
+            Listener::Tcp => &TCP,
+            Listener::WebSocket => &WEBSOCKET,
+            Listener::Irc => &IRC,
+        }
+    }
+}
+
+/// Marks an accept loop as running until the guard is dropped, which also
+/// happens when its task panics.
+pub struct Running(Listener);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.flag().store(false, Ordering::Relaxed);
+    }
+}
+
+pub fn running(listener: Listener) -> Running {
+    listener.flag().store(true, Ordering::Relaxed);
+    Running(listener)
+}
+
+/// Tells the accept loops to stop and readiness checks to fail.
+pub fn begin_shutdown() {
+    shutdown_sender().send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *shutdown_sender().borrow()
+}
+
+/// Resolves once the server starts shutting down.
+pub async fn shutdown() {
+    let mut receiver = shutdown_sender().subscribe();
+    while !*receiver.borrow_and_update() {
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub status: &'static str,
+    pub database: DatabaseCheck,
+    pub tcp: bool,
+    pub websocket: bool,
+    /// `None` when the IRC gateway is turned off.
+    pub irc: Option<bool>,
+    pub shutting_down: bool,
+}
+
+#[derive(Serialize)]
+pub struct DatabaseCheck {
+    pub ok: bool,
+    pub latency_ms: u128,
+    pub error: Option<String>,
+}
+
+/// Checks the database and the accept loops. The server is healthy while
+/// they work, and ready while it is also not shutting down.
+pub async fn check(conn: &Arc<Mutex<Connection>>, readiness: bool) -> Report {
+    let started = Instant::now();
+    let error = match tokio::time::timeout(DATABASE_TIMEOUT, database::ping(conn)).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    let database = DatabaseCheck {
+        ok: error.is_none(),
+        latency_ms: started.elapsed().as_millis(),
+        error,
+    };
+    let tcp = TCP.load(Ordering::Relaxed);
+    let websocket = WEBSOCKET.load(Ordering::Relaxed);
+    let irc = config::get().irc_addr.map(|_| IRC.load(Ordering::Relaxed));
+    let shutting_down = is_shutting_down();
+
+    // The accept loops stop on purpose once the server shuts down.
+    let listening = tcp && websocket && irc.unwrap_or(true);
+    let healthy = database.ok && (listening || shutting_down);
+    let ok = healthy && !(readiness && shutting_down);
+    Report {
+        status: if ok { "ok" } else { "fail" },
+        database,
+        tcp,
+        websocket,
+        irc,
+        shutting_down,
+    }
+}
@@ -0,0 +1,1036 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+use colored::*;
+use futures::SinkExt;
+use rusqlite::Connection;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::Instrument;
+
+use crate::events::ChatEvent;
+use crate::health::{self, Listener};
+use crate::logging;
+use crate::login::{self, Attempt};
+use crate::message::Message;
+use crate::metrics::{self, Transport};
+use crate::presence::PresenceState;
+use crate::{config, database, Outgoing, Shared, Tx};
+
+/// Name the gateway uses for itself and as the host of every user.
+const SERVER: &str = "ferrum";
+/// Longest line accepted from a client, well above the 512 bytes IRC allows.
+const MAX_LINE: usize = 8192;
+
+/// Accepts IRC clients on the configured address. They log in with their
+/// account password as the server password and join the chat by joining
+/// the configured channel, after which they are ordinary peers.
+pub async fn serve(state: Arc<Mutex<Shared>>, conn: Arc<Mutex<Connection>>) {
+    let Some(addr) = config::get().irc_addr else {
+        return;
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("failed to bind the IRC gateway to {}: {:?}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("IRC gateway listening on {}", addr);
+    let _running = health::running(Listener::Irc);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = health::shutdown() => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("failed to accept an IRC connection: {:?}", e);
+                continue;
+            }
+        };
+        metrics::connection(Transport::Irc);
+        crate::set_keepalive(&stream, addr);
+        let state = state.clone();
+        let conn = conn.clone();
+        tokio::spawn(
+            async move {
+                tracing::debug!("accepted IRC connection from {}", addr);
+                if let Err(e) = process(state, conn, stream, addr).await {
+                    tracing::error!(
+                        "an error occurred on IRC connection {}; error = {:?}",
+                        addr,
+                        e
+                    );
+                }
+            }
+            .instrument(logging::connection_span(addr)),
+        );
+    }
+}
+
+/// Splits incoming lines on `\n`, tolerating missing `\r` and invalid
+/// UTF-8, and ends outgoing lines with `\r\n`. Line breaks and NULs inside
+/// an outgoing line, which chat text can contain, are sent as spaces so
+/// they cannot start a protocol line of their own.
+pub struct IrcCodec;
+
+impl Decoder for IrcCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                let line = buf.split_to(end + 1);
+                let line = String::from_utf8_lossy(&line[..end]);
+                Ok(Some(line.trim_end_matches('\r').to_string()))
+            }
+            None if buf.len() > MAX_LINE => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "IRC line too long",
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<String> for IrcCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), io::Error> {
+        buf.reserve(line.len() + 2);
+        buf.extend(line.bytes().map(|b| match b {
+            b'\r' | b'\n' | b'\0' => b' ',
+            b => b,
+        }));
+        buf.put(&b"\r\n"[..]);
+        Ok(())
+    }
+}
+
+type Lines = Framed<TcpStream, IrcCodec>;
+
+/// One line from a client: `[:prefix] COMMAND param... [:trailing]`.
+struct Command {
+    name: String,
+    params: Vec<String>,
+}
+
+fn parse(line: &str) -> Option<Command> {
+    let mut rest = line.trim_start();
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split_whitespace();
+    let name = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(str::to_string).collect();
+    params.extend(trailing.map(str::to_string));
+    Some(Command { name, params })
+}
+
+/// `nick!user@host` as IRC clients expect it in front of relayed lines.
+fn mask(username: &str) -> String {
+    format!("{0}!{0}@{1}", username, SERVER)
+}
+
+fn is_channel(name: &str) -> bool {
+    name.eq_ignore_ascii_case(&config::get().irc_channel)
+}
+
+/// Drops the colors and bells meant for telnet clients.
+fn plain(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            }
+            '\x07' => {}
+            c => plain.push(c),
+        }
+    }
+    plain
+}
+
+async fn process(
+    state: Arc<Mutex<Shared>>,
+    conn: Arc<Mutex<Connection>>,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let mut lines = Framed::new(stream, IrcCodec);
+    let Some(username) = register(&state, &conn, &mut lines, addr).await? else {
+        return Ok(());
+    };
+    logging::logged_in(&username);
+    tracing::info!("{} logged in over IRC from {}", username, addr);
+    database::record_login(&conn, &username).await?;
+
+    let (tx, mut rx) = crate::channel();
+    state.lock().await.enter_lobby(addr, &username, tx.clone());
+    let mut client = Client {
+        lines,
+        username,
+        addr,
+        tx,
+        joined: false,
+    };
+    client.welcome(&conn).await?;
+
+    let idle_timeout = config::get().idle_timeout;
+    let ping_interval = config::get().irc_ping_interval;
+    let mut check = tokio::time::interval(
+        [idle_timeout, ping_interval]
+            .into_iter()
+            .flatten()
+            .fold(Duration::from_secs(30), Duration::min),
+    );
+    // Any line answers a ping, but only commands other than PING and PONG
+    // count as activity for the idle timeout.
+    let mut last_heard = Instant::now();
+    let mut last_active = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                if idle_timeout.is_some_and(|timeout| last_active.elapsed() >= timeout) {
+                    tracing::info!("disconnecting {} after being idle on IRC", client.username);
+                    client.send("ERROR :Closing link: Idle for too long".to_string()).await?;
+                    break;
+                }
+                if let Some(interval) = ping_interval {
+                    let silent = last_heard.elapsed();
+                    if silent >= interval * 2 {
+                        tracing::info!("closing IRC connection of {} after a ping timeout", client.username);
+                        client.send("ERROR :Closing link: Ping timeout".to_string()).await?;
+                        break;
+                    }
+                    if silent >= interval {
+                        client.send(format!("PING :{}", SERVER)).await?;
+                    }
+                }
+            }
+            Some(message) = rx.recv() => {
+                if !client.deliver(&conn, message).await? {
+                    break;
+                }
+            }
+            result = client.lines.next() => match result {
+                Some(Ok(line)) => {
+                    let Some(command) = parse(&line) else {
+                        continue;
+                    };
+                    last_heard = Instant::now();
+                    if !matches!(command.name.as_str(), "PING" | "PONG") {
+                        last_active = Instant::now();
+                    }
+                    if !client.handle(&state, &conn, command).await? {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    tracing::warn!("closing IRC connection of {}; error = {:?}", client.username, e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    client.leave(&state, &conn).await;
+    state.lock().await.remove_session(addr);
+    Ok(())
+}
+
+/// Collects `PASS`, `NICK` and `USER`, then logs in as the nick with the
+/// server password. Returns the account name, or `None` if the client gave
+/// up or was turned away.
+async fn register(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    lines: &mut Lines,
+    addr: SocketAddr,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let mut password = None;
+    let mut nick = None;
+    let mut user = false;
+
+    while let Some(line) = next_line(lines).await {
+        let Some(command) = parse(&line?) else {
+            continue;
+        };
+        let mut params = command.params.into_iter();
+        match command.name.as_str() {
+            "CAP" => match params.next().as_deref() {
+                Some("LS") => lines.send(format!(":{} CAP * LS :", SERVER)).await?,
+                Some("REQ") => {
+                    let requested = params.next().unwrap_or_default();
+                    lines
+                        .send(format!(":{} CAP * NAK :{}", SERVER, requested))
+                        .await?
+                }
+                _ => {}
+            },
+            "PING" => {
+                let token = params.next().unwrap_or_default();
+                lines
+                    .send(format!(":{0} PONG {0} :{1}", SERVER, token))
+                    .await?
+            }
+            "PASS" => password = params.next(),
+            "NICK" => nick = params.next(),
+            "USER" => user = true,
+            "QUIT" => return Ok(None),
+            _ => {
+                lines
+                    .send(format!(":{} 451 * :You have not registered", SERVER))
+                    .await?
+            }
+        }
+
+        if let (Some(nick), true) = (&nick, user) {
+            let result = authenticate(state, conn, addr, nick, password.as_deref()).await?;
+            return match result {
+                Ok(username) => Ok(Some(username)),
+                Err(reason) => {
+                    tracing::info!("failed IRC login as {} from {}", nick, addr);
+                    lines
+                        .send(format!(":{} 464 {} :{}", SERVER, nick, reason))
+                        .await?;
+                    lines
+                        .send(format!("ERROR :Closing link: {}", reason))
+                        .await?;
+                    Ok(None)
+                }
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the next line before the client is logged in, giving up when it
+/// stays idle for longer than the configured timeout.
+async fn next_line(lines: &mut Lines) -> Option<io::Result<String>> {
+    match config::get().idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, lines.next())
+            .await
+            .unwrap_or(None),
+        None => lines.next().await,
+    }
+}
+
+/// Applies the same checks as a telnet login. Returns the account name, or
+/// why the login was refused.
+async fn authenticate(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    addr: SocketAddr,
+    nick: &str,
+    password: Option<&str>,
+) -> Result<Result<String, String>, Box<dyn Error>> {
+    let Some(password) = password else {
+        return Ok(Err(
+            "Use your account password as the server password".to_string()
+        ));
+    };
+    if !database::authenticate_user(conn, nick, password).await? {
+        metrics::login(false);
+        return Ok(Err("Password incorrect".to_string()));
+    }
+    let Some(profile) = database::get_user_profile(conn, nick).await? else {
+        return Ok(Err("Password incorrect".to_string()));
+    };
+    let attempt = match login::check_account(&profile) {
+        Some(rejection) => rejection,
+        None if profile.must_change_password => {
+            return Ok(Err(
+                "Your password was reset, log in over telnet to choose a new one".to_string(),
+            ));
+        }
+        None => login::claim_session(state, addr, profile.username).await,
+    };
+    metrics::login(matches!(attempt, Attempt::Success(_)));
+    Ok(match attempt {
+        Attempt::Success(username) => Ok(username),
+        Attempt::Failed(reason) | Attempt::Rejected(reason) => Err(reason),
+        Attempt::Disconnected => Err("Disconnected".to_string()),
+    })
+}
+
+/// A logged in IRC connection. It becomes a peer in [`Shared`] while it is
+/// in the channel and waits in the lobby otherwise.
+struct Client {
+    lines: Lines,
+    username: String,
+    addr: SocketAddr,
+    tx: Tx,
+    joined: bool,
+}
+
+impl Client {
+    async fn send(&mut self, line: String) -> io::Result<()> {
+        self.lines.send(line).await
+    }
+
+    async fn numeric(&mut self, code: &str, text: &str) -> io::Result<()> {
+        let line = format!(":{} {} {} {}", SERVER, code, self.username, text);
+        self.send(line).await
+    }
+
+    async fn notice(&mut self, text: &str) -> io::Result<()> {
+        let line = format!(":{} NOTICE {} :{}", SERVER, self.username, text);
+        self.send(line).await
+    }
+
+    async fn welcome(&mut self, conn: &Arc<Mutex<Connection>>) -> Result<(), Box<dyn Error>> {
+        let version = env!("CARGO_PKG_VERSION");
+        let channel = &config::get().irc_channel;
+        self.numeric(
+            "001",
+            &format!(":Welcome to FerrumServe, {}", self.username),
+        )
+        .await?;
+        self.numeric(
+            "002",
+            &format!(":Your host is {}, running FerrumServe {}", SERVER, version),
+        )
+        .await?;
+        self.numeric("004", &format!("{} {} o bq", SERVER, version))
+            .await?;
+        self.numeric(
+            "005",
+            "CHANTYPES=# PREFIX=(o)@ CHANMODES=bq,,,nt NETWORK=FerrumServe CASEMAPPING=ascii :are supported by this server",
+        )
+        .await?;
+        self.numeric("422", ":MOTD File is missing").await?;
+        self.notice(&format!("Join {} to take part in the chat.", channel))
+            .await?;
+
+        let unseen_mentions = database::count_unseen_mentions(conn, &self.username).await?;
+        if unseen_mentions > 0 {
+            self.notice(&format!(
+                "You were mentioned {} time(s) while away.",
+                unseen_mentions
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Relays what the chat sends to this peer. Returns `false` once the
+    /// connection has to be closed.
+    async fn deliver(
+        &mut self,
+        conn: &Arc<Mutex<Connection>>,
+        message: Outgoing,
+    ) -> Result<bool, Box<dyn Error>> {
+        let channel = &config::get().irc_channel;
+        match message {
+            Outgoing::Line(text) => {
+                for line in plain(&text).lines().map(str::trim) {
+                    if !line.is_empty() {
+                        self.notice(line).await?;
+                    }
+                }
+            }
+            Outgoing::Chat(message, _) => {
+                let text = match message.parent_id {
+                    Some(parent) => format!("(re #{}) {}", parent, message.content),
+                    None => message.content.clone(),
+                };
+                self.send(format!(
+                    ":{} PRIVMSG {} :{}",
+                    mask(&message.sender),
+                    channel,
+                    text
+                ))
+                .await?;
+                if message.mentions().contains(&self.username) {
+                    database::mark_mentions_seen(conn, &self.username, message.id).await?;
+                }
+            }
+            Outgoing::Whisper(sender, text) => {
+                let line = format!(":{} PRIVMSG {} :{}", mask(&sender), self.username, text);
+                self.send(line).await?;
+            }
+            Outgoing::Joined(user) => {
+                self.send(format!(":{} JOIN {}", mask(&user), channel))
+                    .await?;
+            }
+            Outgoing::Left(user) => {
+                self.send(format!(":{} PART {} :Left the chat", mask(&user), channel))
+                    .await?;
+            }
+            Outgoing::Disconnect(notice) => {
+                self.send(format!("ERROR :Closing link: {}", plain(&notice)))
+                    .await?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs one client command. Returns `false` once the client quits.
+    async fn handle(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        command: Command,
+    ) -> Result<bool, Box<dyn Error>> {
+        let params = command.params;
+        match command.name.as_str() {
+            "PING" => {
+                let token = params.first().cloned().unwrap_or_default();
+                self.send(format!(":{0} PONG {0} :{1}", SERVER, token))
+                    .await?;
+            }
+            "PONG" | "CAP" => {}
+            "PASS" | "USER" => self.numeric("462", ":You may not reregister").await?,
+            "NICK" => {
+                self.numeric("447", ":Use /rename over telnet to change your name")
+                    .await?
+            }
+            "JOIN" => match params.first() {
+                Some(channels) if channels == "0" => self.part_all(state, conn).await?,
+                Some(channels) => {
+                    for channel in channels.split(',') {
+                        self.join(state, conn, channel).await?;
+                    }
+                }
+                None => self.numeric("461", "JOIN :Not enough parameters").await?,
+            },
+            "PART" => match params.first() {
+                Some(channels) => {
+                    for channel in channels.split(',') {
+                        self.part(state, conn, channel).await?;
+                    }
+                }
+                None => self.numeric("461", "PART :Not enough parameters").await?,
+            },
+            "PRIVMSG" | "NOTICE" => {
+                self.privmsg(state, conn, command.name == "NOTICE", &params)
+                    .await?
+            }
+            "NAMES" => self.names(state, conn).await?,
+            "WHO" => self.who(state, conn, params.first()).await?,
+            "WHOIS" => match params.last() {
+                Some(nick) => self.whois(state, conn, nick).await?,
+                None => self.numeric("431", ":No nickname given").await?,
+            },
+            "TOPIC" => {
+                let channel = &config::get().irc_channel;
+                self.numeric("331", &format!("{} :No topic is set", channel))
+                    .await?
+            }
+            "AWAY" => self.away(state, params.first()).await?,
+            "MODE" => self.mode(state, conn, &params).await?,
+            "KICK" => self.kick(state, conn, &params).await?,
+            "QUIT" => return Ok(false),
+            name => {
+                self.numeric("421", &format!("{} :Unknown command", name))
+                    .await?
+            }
+        }
+        Ok(true)
+    }
+
+    /// Joining the channel enters the chat.
+    async fn join(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        channel: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if !is_channel(channel) {
+            self.numeric("403", &format!("{} :No such channel", channel))
+                .await?;
+            return Ok(());
+        }
+        if self.joined {
+            return Ok(());
+        }
+        self.joined = true;
+        {
+            let mut state = state.lock().await;
+            state.lobby.remove(&self.addr);
+            state.add_session(self.addr, &self.username, self.tx.clone(), Transport::Irc);
+            state.announce_join(self.addr, &self.username);
+        }
+        let channel = &config::get().irc_channel;
+        self.send(format!(":{} JOIN {}", mask(&self.username), channel))
+            .await?;
+        self.numeric("331", &format!("{} :No topic is set", channel))
+            .await?;
+        self.names(state, conn).await
+    }
+
+    /// Parting the channel leaves the chat, but keeps the connection open.
+    async fn part(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        channel: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if !is_channel(channel) {
+            self.numeric("403", &format!("{} :No such channel", channel))
+                .await?;
+        } else if !self.joined {
+            self.numeric("442", &format!("{} :You're not on that channel", channel))
+                .await?;
+        } else {
+            self.part_all(state, conn).await?;
+        }
+        Ok(())
+    }
+
+    async fn part_all(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.joined {
+            let channel = &config::get().irc_channel;
+            self.send(format!(":{} PART {}", mask(&self.username), channel))
+                .await?;
+            self.leave(state, conn).await;
+        }
+        Ok(())
+    }
+
+    async fn leave(&mut self, state: &Arc<Mutex<Shared>>, conn: &Arc<Mutex<Connection>>) {
+        if !self.joined {
+            return;
+        }
+        self.joined = false;
+        let mut state = state.lock().await;
+        state.enter_lobby(self.addr, &self.username, self.tx.clone());
+        state.announce_leave(conn, &self.username).await;
+    }
+
+    /// Messages to the channel are posted to the chat, messages to a nick
+    /// are whispers. `NOTICE` never gets error replies.
+    async fn privmsg(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        notice: bool,
+        params: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let (Some(target), Some(text)) = (params.first(), params.get(1)) else {
+            if !notice {
+                self.numeric("412", ":No text to send").await?;
+            }
+            return Ok(());
+        };
+        let text = match text.strip_prefix("\x01ACTION ") {
+            Some(action) => format!("*{}*", action.trim_end_matches('\x01')),
+            // Other CTCP requests such as VERSION have no chat equivalent.
+            None if text.starts_with('\x01') => return Ok(()),
+            None => text.clone(),
+        };
+
+        if is_channel(target) {
+            if self.joined {
+                self.post(state, conn, target, text).await?;
+            } else if !notice {
+                self.numeric("404", &format!("{} :Cannot send to channel", target))
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        let state = state.lock().await;
+        let Some(user) = find_connected(&state, target) else {
+            drop(state);
+            if !notice {
+                self.numeric("401", &format!("{} :No such nick/channel", target))
+                    .await?;
+            }
+            return Ok(());
+        };
+        state
+            .send_to_user(&user, Outgoing::Whisper(self.username.clone(), text))
+            .await;
+        let away = state
+            .presence
+            .get(&user)
+            .filter(|presence| presence.state != PresenceState::Online)
+            .and_then(|presence| presence.auto_reply(&user));
+        drop(state);
+        if let Some(reply) = away.filter(|_| !notice) {
+            self.numeric("301", &format!("{} :{}", user, reply)).await?;
+        }
+        Ok(())
+    }
+
+    async fn post(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        channel: &str,
+        text: String,
+    ) -> Result<(), Box<dyn Error>> {
+        if database::get_user_role(conn, &self.username).await? == "muted" {
+            self.numeric("404", &format!("{} :You are muted", channel))
+                .await?;
+            return Ok(());
+        }
+        let mut message = Message::from_input(self.username.clone(), text);
+        let id = database::store_message(conn, &mut message).await?;
+        database::store_mentions(conn, id, &message.mentions()).await?;
+        let color = database::get_user_settings(conn, &self.username)
+            .await
+            .unwrap_or_default()
+            .color();
+
+        let mut state = state.lock().await;
+        if let Some(session) = state.sessions.get_mut(&self.addr) {
+            session.last_active = Instant::now();
+        }
+        if state.presence_mut(&self.username).auto_away {
+            state.presence_mut(&self.username).set_back();
+        }
+        state.broadcast_message(self.addr, &message, color).await;
+        Ok(())
+    }
+
+    /// Everyone in the chat, with admins as channel operators.
+    async fn names(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let channel = &config::get().irc_channel;
+        let users: BTreeSet<String> = state.lock().await.usernames.values().cloned().collect();
+        let mut names = Vec::new();
+        for user in users {
+            let admin = database::get_user_role(conn, &user)
+                .await
+                .unwrap_or_default()
+                == "admin";
+            names.push(format!("{}{}", if admin { "@" } else { "" }, user));
+        }
+        for chunk in names.chunks(50) {
+            self.numeric("353", &format!("= {} :{}", channel, chunk.join(" ")))
+                .await?;
+        }
+        self.numeric("366", &format!("{} :End of /NAMES list", channel))
+            .await?;
+        Ok(())
+    }
+
+    async fn who(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        mask: Option<&String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let channel = &config::get().irc_channel;
+        let mask = mask.map(String::as_str).unwrap_or("*");
+        let users: Vec<(String, bool)> = {
+            let state = state.lock().await;
+            let names: BTreeSet<&String> = state.usernames.values().collect();
+            names
+                .into_iter()
+                .filter(|user| is_channel(mask) || mask == "*" || user.eq_ignore_ascii_case(mask))
+                .map(|user| {
+                    let away = state
+                        .presence
+                        .get(user)
+                        .is_some_and(|presence| presence.state != PresenceState::Online);
+                    (user.clone(), away)
+                })
+                .collect()
+        };
+        for (user, away) in users {
+            let admin = database::get_user_role(conn, &user)
+                .await
+                .unwrap_or_default()
+                == "admin";
+            let flags = format!(
+                "{}{}",
+                if away { "G" } else { "H" },
+                if admin { "@" } else { "" }
+            );
+            self.numeric(
+                "352",
+                &format!(
+                    "{0} {1} {2} {2} {1} {3} :0 {1}",
+                    channel, user, SERVER, flags
+                ),
+            )
+            .await?;
+        }
+        self.numeric("315", &format!("{} :End of /WHO list", mask))
+            .await?;
+        Ok(())
+    }
+
+    async fn whois(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        nick: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let (user, away) = {
+            let state = state.lock().await;
+            let user = find_connected(&state, nick);
+            let away = user
+                .as_ref()
+                .and_then(|user| state.presence.get(user))
+                .and_then(|presence| presence.auto_reply(nick));
+            (user, away)
+        };
+        match user {
+            Some(user) => {
+                self.numeric("311", &format!("{0} {0} {1} * :{0}", user, SERVER))
+                    .await?;
+                self.numeric("312", &format!("{} {} :FerrumServe", user, SERVER))
+                    .await?;
+                if database::get_user_role(conn, &user).await? == "admin" {
+                    self.numeric("313", &format!("{} :is an admin", user))
+                        .await?;
+                }
+                if let Some(away) = away {
+                    self.numeric("301", &format!("{} :{}", user, away)).await?;
+                }
+            }
+            None => {
+                self.numeric("401", &format!("{} :No such nick/channel", nick))
+                    .await?;
+            }
+        }
+        self.numeric("318", &format!("{} :End of /WHOIS list", nick))
+            .await?;
+        Ok(())
+    }
+
+    async fn away(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        message: Option<&String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let message = message.filter(|message| !message.is_empty()).cloned();
+        let away = message.is_some();
+        {
+            let mut state = state.lock().await;
+            let presence = state.presence_mut(&self.username);
+            match message {
+                Some(message) => presence.set_away(Some(message), false),
+                None => presence.set_back(),
+            }
+        }
+        if away {
+            self.numeric("306", ":You have been marked as being away")
+                .await?;
+        } else {
+            self.numeric("305", ":You are no longer marked as being away")
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Channel modes `+b`/`-b` ban and unban, `+q`/`-q` mute and unmute.
+    async fn mode(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        params: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(target) = params.first() else {
+            self.numeric("461", "MODE :Not enough parameters").await?;
+            return Ok(());
+        };
+        if !is_channel(target) {
+            if target.eq_ignore_ascii_case(&self.username) {
+                self.numeric("221", "+").await?;
+            } else {
+                self.numeric("502", ":Cannot change mode for other users")
+                    .await?;
+            }
+            return Ok(());
+        }
+        let channel = &config::get().irc_channel;
+        let (Some(modes), Some(nick)) = (params.get(1), params.get(2)) else {
+            match params.get(1).map(|modes| modes.trim_start_matches('+')) {
+                Some("b") => {
+                    self.numeric("368", &format!("{} :End of channel ban list", channel))
+                        .await?
+                }
+                Some(_) => self.numeric("461", "MODE :Not enough parameters").await?,
+                None => self.numeric("324", &format!("{} +nt", channel)).await?,
+            }
+            return Ok(());
+        };
+        if database::get_user_role(conn, &self.username).await? != "admin" {
+            self.numeric("482", &format!("{} :You're not channel operator", channel))
+                .await?;
+            return Ok(());
+        }
+        let role = match modes.as_str() {
+            "+b" => "banned",
+            "+q" => "muted",
+            "-b" | "-q" => "user",
+            _ => {
+                self.numeric("472", &format!("{} :is unknown mode char to me", modes))
+                    .await?;
+                return Ok(());
+            }
+        };
+        let Some(profile) = database::get_user_profile(conn, nick).await? else {
+            self.numeric("401", &format!("{} :No such nick/channel", nick))
+                .await?;
+            return Ok(());
+        };
+        database::change_role(conn, &profile.username, role).await?;
+        if role == "banned" {
+            let state = state.lock().await;
+            state
+                .send_to_user(
+                    &profile.username,
+                    Outgoing::Disconnect("You have been banned.".red().to_string()),
+                )
+                .await;
+            state.publish(ChatEvent::UserBanned {
+                username: profile.username.clone(),
+                by: self.username.clone(),
+            });
+        }
+        tracing::info!(
+            "{} set {} on {} over IRC",
+            self.username,
+            modes,
+            profile.username
+        );
+        let line = format!(
+            ":{} MODE {} {} {}",
+            mask(&self.username),
+            channel,
+            modes,
+            profile.username
+        );
+        self.send(line).await?;
+        Ok(())
+    }
+
+    /// Kicking disconnects every session of the user.
+    async fn kick(
+        &mut self,
+        state: &Arc<Mutex<Shared>>,
+        conn: &Arc<Mutex<Connection>>,
+        params: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let (Some(channel), Some(nick)) = (params.first(), params.get(1)) else {
+            self.numeric("461", "KICK :Not enough parameters").await?;
+            return Ok(());
+        };
+        if !is_channel(channel) {
+            self.numeric("403", &format!("{} :No such channel", channel))
+                .await?;
+            return Ok(());
+        }
+        if database::get_user_role(conn, &self.username).await? != "admin" {
+            self.numeric("482", &format!("{} :You're not channel operator", channel))
+                .await?;
+            return Ok(());
+        }
+        let reason = params
+            .get(2)
+            .cloned()
+            .unwrap_or_else(|| self.username.clone());
+        let state = state.lock().await;
+        let Some(user) = find_connected(&state, nick) else {
+            drop(state);
+            self.numeric(
+                "441",
+                &format!("{} {} :They aren't on that channel", nick, channel),
+            )
+            .await?;
+            return Ok(());
+        };
+        let notice = format!("You have been kicked by {}: {}", self.username, reason);
+        state
+            .send_to_user(&user, Outgoing::Disconnect(notice.red().to_string()))
+            .await;
+        drop(state);
+        tracing::info!("{} kicked {} over IRC", self.username, user);
+        let channel = &config::get().irc_channel;
+        let line = format!(
+            ":{} KICK {} {} :{}",
+            mask(&self.username),
+            channel,
+            user,
+            reason
+        );
+        self.send(line).await?;
+        Ok(())
+    }
+}
+
+/// The connected user a nick refers to, ignoring case.
+fn find_connected(state: &Shared, nick: &str) -> Option<String> {
+    state
+        .usernames
+        .values()
+        .find(|user| user.eq_ignore_ascii_case(nick))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_replaces_line_breaks_inside_a_line() {
+        let mut buf = BytesMut::new();
+        let line = ":a!a@ferrum PRIVMSG #ferrum :hi\r\nERROR :spoofed\0".to_string();
+        IrcCodec.encode(line, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            b":a!a@ferrum PRIVMSG #ferrum :hi  ERROR :spoofed \r\n"
+        );
+    }
+
+    #[test]
+    fn decode_tolerates_missing_carriage_return() {
+        let mut buf = BytesMut::from(&b"NICK alice\nUSER a 0 * :A\r\nPI"[..]);
+        assert_eq!(IrcCodec.decode(&mut buf).unwrap().unwrap(), "NICK alice");
+        assert_eq!(IrcCodec.decode(&mut buf).unwrap().unwrap(), "USER a 0 * :A");
+        assert_eq!(IrcCodec.decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"PI");
+    }
+
+    #[test]
+    fn parse_splits_prefix_params_and_trailing() {
+        let command = parse(":alice PRIVMSG #ferrum :hello :) there").unwrap();
+        assert_eq!(command.name, "PRIVMSG");
+        assert_eq!(command.params, ["#ferrum", "hello :) there"]);
+
+        let command = parse("join  #ferrum").unwrap();
+        assert_eq!(command.name, "JOIN");
+        assert_eq!(command.params, ["#ferrum"]);
+
+        assert!(parse("").is_none());
+        assert!(parse(":prefix-only").is_none());
+    }
+
+    #[test]
+    fn plain_drops_colors_and_bells() {
+        assert_eq!(plain("\x1b[1;31mred\x1b[0m\x07 text"), "red text");
+    }
+}
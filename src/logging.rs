@@ -0,0 +1,74 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tracing::Span;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::{config, metrics};
+
+/// Name of the log files, which get the date appended when rotated.
+const LOG_FILE: &str = "ferrum-serve.log";
+
+/// Installs the global subscriber. `RUST_LOG` takes precedence over the
+/// configured level. The returned guard flushes the log file when dropped,
+/// so it has to be held until the server exits.
+pub fn init() -> Option<WorkerGuard> {
+    let config = config::get();
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+
+    let (writer, guard) = match &config.log_dir {
+        Some(dir) => {
+            let rotation = match config.log_rotation.to_ascii_lowercase().as_str() {
+                "minutely" => Rotation::MINUTELY,
+                "hourly" => Rotation::HOURLY,
+                "never" => Rotation::NEVER,
+                _ => Rotation::DAILY,
+            };
+            let appender = RollingFileAppender::new(rotation, dir, LOG_FILE);
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(io::stdout), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(guard.is_none());
+    if config.log_json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+    guard
+}
+
+/// Span around everything logged for one connection. `username` is empty
+/// until the peer logs in and is recorded with `logged_in`. For sessions
+/// proxied from the WebSocket port, `peer` is the proxy's own address and
+/// `client` the WebSocket client's, recorded with `proxied`.
+pub fn connection_span(addr: SocketAddr) -> Span {
+    tracing::info_span!(
+        "connection",
+        peer = %addr,
+        client = tracing::field::Empty,
+        username = tracing::field::Empty
+    )
+}
+
+pub fn logged_in(username: &str) {
+    Span::current().record("username", username);
+}
+
+/// Records the WebSocket client behind a proxied connection. Only reliable
+/// once the connection has sent something, since the proxy registers itself
+/// before forwarding.
+pub fn proxied(addr: SocketAddr) {
+    if let Some(client) = metrics::websocket_client(addr) {
+        Span::current().record("client", tracing::field::display(client));
+    }
+}
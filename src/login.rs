@@ -0,0 +1,342 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use colored::*;
+use futures::SinkExt;
+use rusqlite::Connection;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+use crate::database::{AccountError, UserProfile};
+use crate::telnet::{self, Negotiation, TelnetCodec, ECHO};
+use crate::tokens::Scope;
+use crate::{config, database, health, logging, metrics, Outgoing, Shared};
+
+type Lines = Framed<TcpStream, TelnetCodec>;
+
+/// Walks a new connection through logging in or registering, allowing a few
+/// failed attempts. Returns the username once the client is authenticated,
+/// or `None` if it gave up or disconnected.
+pub async fn login(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    lines: &mut Lines,
+    addr: SocketAddr,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let max_attempts = config::get().max_login_attempts;
+
+    for attempt in 1..=max_attempts {
+        lines
+            .send(
+                "Type 'login' or 'register' (or 'login <username> <password>'):"
+                    .blue()
+                    .to_string(),
+            )
+            .await?;
+
+        let Some(line) = read_line(lines).await else {
+            tracing::info!("{} disconnected during login", addr);
+            return Ok(None);
+        };
+        if attempt == 1 {
+            logging::proxied(addr);
+        }
+        let mut parts = line.trim_start().splitn(3, ' ');
+        let action = parts.next().unwrap_or_default().to_lowercase();
+        let username = parts.next().map(str::to_string);
+        let password = parts.next().map(str::to_string);
+
+        let result = match action.as_str() {
+            "login" => login_user(state, conn, lines, addr, username, password).await?,
+            "register" => register_user(conn, lines, username, password).await?,
+            "token" => login_token(state, conn, lines, addr, username).await?,
+            _ => Attempt::Failed("Invalid command, use 'login' or 'register'.".to_string()),
+        };
+
+        metrics::login(matches!(result, Attempt::Success(_)));
+        match result {
+            Attempt::Success(username) => return Ok(Some(username)),
+            Attempt::Disconnected => return Ok(None),
+            Attempt::Rejected(reason) => {
+                lines.send(reason.red().to_string()).await?;
+                return Ok(None);
+            }
+            Attempt::Failed(reason) => {
+                tracing::info!("failed login attempt {} from {}", attempt, addr);
+                lines.send(reason.red().to_string()).await?;
+            }
+        }
+    }
+
+    lines
+        .send("Too many failed attempts, goodbye.".red().to_string())
+        .await?;
+    Ok(None)
+}
+
+pub enum Attempt {
+    Success(String),
+    /// The client may try again.
+    Failed(String),
+    /// The connection is closed after telling the client why.
+    Rejected(String),
+    Disconnected,
+}
+
+async fn login_user(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    lines: &mut Lines,
+    addr: SocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<Attempt, Box<dyn Error>> {
+    let Some(username) = prompt_or(lines, username, "Username:").await? else {
+        return Ok(Attempt::Disconnected);
+    };
+    let Some(password) = prompt_password_or(lines, password, "Password:").await? else {
+        return Ok(Attempt::Disconnected);
+    };
+
+    if !database::authenticate_user(conn, &username, &password).await? {
+        return Ok(Attempt::Failed(
+            "Authentication failed, please try again.".to_string(),
+        ));
+    }
+    let Some(profile) = database::get_user_profile(conn, &username).await? else {
+        return Ok(Attempt::Failed(
+            "Authentication failed, please try again.".to_string(),
+        ));
+    };
+    if let Some(rejection) = check_account(&profile) {
+        return Ok(rejection);
+    }
+    if profile.must_change_password {
+        match change_temporary_password(conn, lines, &username).await? {
+            Attempt::Success(_) => {}
+            attempt => return Ok(attempt),
+        }
+    }
+    Ok(claim_session(state, addr, username).await)
+}
+
+/// Logs in with an API token that has the `chat` scope, for bots.
+async fn login_token(
+    state: &Arc<Mutex<Shared>>,
+    conn: &Arc<Mutex<Connection>>,
+    lines: &mut Lines,
+    addr: SocketAddr,
+    token: Option<String>,
+) -> Result<Attempt, Box<dyn Error>> {
+    let Some(token) = prompt_password_or(lines, token, "Token:").await? else {
+        return Ok(Attempt::Disconnected);
+    };
+    let info = database::authenticate_token(conn, token.trim()).await?;
+    let Some(info) = info.filter(|info| info.scopes.contains(&Scope::Chat)) else {
+        return Ok(Attempt::Failed(
+            "Invalid or expired token, please try again.".to_string(),
+        ));
+    };
+    let Some(profile) = database::get_user_profile(conn, &info.username).await? else {
+        return Ok(Attempt::Failed(
+            "Invalid or expired token, please try again.".to_string(),
+        ));
+    };
+    if let Some(rejection) = check_account(&profile) {
+        return Ok(rejection);
+    }
+    tracing::info!("{} logged in with token {}", profile.username, info.id);
+    Ok(claim_session(state, addr, profile.username).await)
+}
+
+/// Turns away banned and locked accounts.
+pub fn check_account(profile: &UserProfile) -> Option<Attempt> {
+    if profile.role == "banned" {
+        return Some(Attempt::Rejected(
+            "You are banned, please try again later.".to_string(),
+        ));
+    }
+    if profile.locked {
+        return Some(Attempt::Rejected(
+            "This account is locked, please contact an admin.".to_string(),
+        ));
+    }
+    None
+}
+
+/// Applies the session policy when the account is already connected:
+/// reject the login, or take over the existing sessions. No logins are
+/// accepted once the server is shutting down.
+pub async fn claim_session(
+    state: &Arc<Mutex<Shared>>,
+    addr: SocketAddr,
+    username: String,
+) -> Attempt {
+    if health::is_shutting_down() {
+        return Attempt::Rejected("The server is shutting down.".to_string());
+    }
+    let state = state.lock().await;
+    if state.has_session(&username) && !config::get().multiple_sessions {
+        if !config::get().session_takeover {
+            return Attempt::Failed("User already connected, please try again.".to_string());
+        }
+        tracing::info!("{} reclaimed their session from {}", username, addr);
+        let notice = format!(
+            "Your session was taken over by a new login from {}.",
+            addr.ip()
+        );
+        state
+            .send_to_user(&username, Outgoing::Disconnect(notice.red().to_string()))
+            .await;
+    }
+    Attempt::Success(username)
+}
+
+/// Makes a user whose password was reset by an admin pick a new one before
+/// the login completes.
+async fn change_temporary_password(
+    conn: &Arc<Mutex<Connection>>,
+    lines: &mut Lines,
+    username: &str,
+) -> Result<Attempt, Box<dyn Error>> {
+    lines
+        .send(
+            "Your password was reset, please choose a new one."
+                .yellow()
+                .to_string(),
+        )
+        .await?;
+    for _ in 0..config::get().max_login_attempts {
+        let Some(password) = prompt_password(lines, "New password:").await? else {
+            return Ok(Attempt::Disconnected);
+        };
+        let Some(confirmation) = prompt_password(lines, "Confirm password:").await? else {
+            return Ok(Attempt::Disconnected);
+        };
+        if password != confirmation {
+            lines
+                .send("Passwords do not match.".red().to_string())
+                .await?;
+            continue;
+        }
+        match database::update_user_password(conn, username, &password).await {
+            Ok(()) => {
+                lines
+                    .send("Password updated successfully.".green().to_string())
+                    .await?;
+                return Ok(Attempt::Success(username.to_string()));
+            }
+            Err(e) => lines.send(e.to_string().red().to_string()).await?,
+        }
+    }
+    Ok(Attempt::Rejected(
+        "Too many failed attempts, goodbye.".to_string(),
+    ))
+}
+
+async fn register_user(
+    conn: &Arc<Mutex<Connection>>,
+    lines: &mut Lines,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<Attempt, Box<dyn Error>> {
+    let Some(username) = prompt_or(lines, username, "Choose a username:").await? else {
+        return Ok(Attempt::Disconnected);
+    };
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let Some(password) = prompt_password(lines, "Choose a password:").await? else {
+                return Ok(Attempt::Disconnected);
+            };
+            let Some(confirmation) = prompt_password(lines, "Confirm password:").await? else {
+                return Ok(Attempt::Disconnected);
+            };
+            if password != confirmation {
+                return Ok(Attempt::Failed("Passwords do not match.".to_string()));
+            }
+            password
+        }
+    };
+
+    // Checked again here, since registering does not go through
+    // `claim_session`.
+    if health::is_shutting_down() {
+        return Ok(Attempt::Rejected(
+            "The server is shutting down.".to_string(),
+        ));
+    }
+    match database::register_user(conn, &username, &password).await {
+        Ok(_) => {
+            tracing::info!("registered user {}", username);
+            lines
+                .send(format!(
+                    "Registration successful, welcome {}!",
+                    username.green().bold()
+                ))
+                .await?;
+            Ok(Attempt::Success(username))
+        }
+        Err(AccountError::Invalid(e)) => Ok(Attempt::Failed(format!("Registration failed: {}", e))),
+        Err(e) => {
+            tracing::error!("registering {} failed: {:?}", username, e);
+            Ok(Attempt::Failed(format!("Registration failed: {}", e)))
+        }
+    }
+}
+
+/// Reads the next line, giving up when the client disconnects or stays idle
+/// for longer than the configured timeout.
+async fn read_line(lines: &mut Lines) -> Option<String> {
+    let line = match config::get().idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, lines.next())
+            .await
+            .unwrap_or(None),
+        None => lines.next().await,
+    };
+    telnet::answer(lines).await.ok()?;
+    line.and_then(Result::ok)
+}
+
+async fn prompt_or(
+    lines: &mut Lines,
+    value: Option<String>,
+    prompt: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(value) = value.filter(|v| !v.trim().is_empty()) {
+        return Ok(Some(value.trim().to_string()));
+    }
+    lines.send(prompt.blue().to_string()).await?;
+    Ok(read_line(lines).await.map(|line| line.trim().to_string()))
+}
+
+async fn prompt_password_or(
+    lines: &mut Lines,
+    value: Option<String>,
+    prompt: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    match value {
+        Some(value) => Ok(Some(value)),
+        None => prompt_password(lines, prompt).await,
+    }
+}
+
+/// Asks for a password with the client's local echo turned off. Plain line
+/// clients that never negotiated telnet options just get the prompt.
+async fn prompt_password(
+    lines: &mut Lines,
+    prompt: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    lines.send(prompt.blue().to_string()).await?;
+    if !lines.codec().is_telnet() {
+        return Ok(read_line(lines).await);
+    }
+    lines.send(Negotiation::Will(ECHO)).await?;
+    let password = read_line(lines).await;
+    lines.send(Negotiation::Wont(ECHO)).await?;
+    lines.send("").await?;
+    Ok(password)
+}
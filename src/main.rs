@@ -1,34 +1,56 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use colored::*;
 use futures::SinkExt;
 use prettytable::{row, Table};
-use rusqlite::{Connection};
+use rusqlite::Connection;
+use socket2::{SockRef, TcpKeepalive};
 use std::str::FromStr;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::signal;
+use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
-use tracing_subscriber::fmt::format::FmtSpan;
+use tokio_util::codec::Framed;
+use tracing::Instrument;
 
+use crate::events::ChatEvent;
+use crate::health::Listener;
 use crate::message::Message;
+use crate::metrics::Transport;
+use crate::presence::Presence;
+use crate::settings::UserSettings;
+use crate::telnet::TelnetCodec;
 
+mod api;
 mod commands;
+mod config;
 mod database;
+mod emoji;
+mod events;
+mod health;
+mod irc;
+mod logging;
+mod login;
 mod message;
+mod metrics;
+mod presence;
+mod settings;
+mod telnet;
+mod tokens;
+mod validation;
+mod webhooks;
 mod websocket;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .with_span_events(FmtSpan::FULL)
-        .init();
+    let _log_guard = logging::init();
 
     let addr = env::args()
         .nth(1)
@@ -56,35 +78,183 @@ _/ ____\__________________ __ __  _____             ______ ______________  __ __
     let state = Arc::new(Mutex::new(Shared::new()));
 
     tokio::spawn(websocket::websocket_proxy());
+    tokio::spawn(api::serve(state.clone(), conn.clone()));
+    tokio::spawn(webhooks::run(state.clone(), conn.clone()));
+    tokio::spawn(irc::serve(state.clone(), conn.clone()));
 
+    let running = health::running(Listener::Tcp);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
+        metrics::connection(Transport::Telnet);
+        set_keepalive(&stream, addr);
         let state_clone = state.clone();
         let conn_clone = conn.clone();
 
-        tokio::spawn(async move {
-            tracing::debug!("accepted connection");
+        tokio::spawn(
+            async move {
+                tracing::debug!("accepted connection");
 
-            if let Err(e) = process(state_clone, conn_clone, stream, addr).await {
-                tracing::error!("an error occurred; error = {:?}", e);
+                if let Err(e) = process(state_clone, conn_clone, stream, addr).await {
+                    tracing::error!("an error occurred; error = {:?}", e);
+                }
             }
-        });
+            .instrument(logging::connection_span(addr)),
+        );
+    }
+
+    tracing::info!("shutting down");
+    drop(listener);
+    drop(running);
+    // Stops the WebSocket and IRC accept loops as well.
+    health::begin_shutdown();
+    {
+        let state = state.lock().await;
+        let notice = "The server is shutting down, goodbye.".yellow().to_string();
+        let lobby = state.lobby.values().map(|(_, tx)| tx);
+        for tx in state.peers.values().chain(lobby) {
+            let _ = tx.send(Outgoing::Disconnect(notice.clone()));
+        }
     }
+    // Stay up a little so readiness probes see the shutdown and sessions
+    // can finish leaving.
+    tokio::time::sleep(config::get().shutdown_grace).await;
+    Ok(())
 }
 
-type Tx = mpsc::UnboundedSender<String>;
-type Rx = mpsc::UnboundedReceiver<String>;
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                tracing::warn!("failed to listen for SIGTERM: {:?}", e);
+                let _ = signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+}
+
+/// Turns on TCP keepalive, if configured, so dead clients are noticed.
+fn set_keepalive(stream: &TcpStream, addr: SocketAddr) {
+    if let Some(keepalive) = config::get().tcp_keepalive {
+        let keepalive = TcpKeepalive::new()
+            .with_time(keepalive)
+            .with_interval(keepalive / 4);
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            tracing::warn!("failed to enable keepalive for {}: {:?}", addr, e);
+        }
+    }
+}
+
+/// Sending half of a peer's queue, counting what it has not sent yet.
+#[derive(Debug, Clone)]
+struct Tx {
+    sender: mpsc::UnboundedSender<Outgoing>,
+    queued: Arc<AtomicUsize>,
+}
+
+struct Rx {
+    receiver: mpsc::UnboundedReceiver<Outgoing>,
+    queued: Arc<AtomicUsize>,
+}
+
+fn channel() -> (Tx, Rx) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    (
+        Tx {
+            sender,
+            queued: queued.clone(),
+        },
+        Rx { receiver, queued },
+    )
+}
+
+impl Tx {
+    fn send(&self, message: Outgoing) -> Result<(), mpsc::error::SendError<Outgoing>> {
+        self.sender.send(message)?;
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+impl Rx {
+    async fn recv(&mut self) -> Option<Outgoing> {
+        let message = self.receiver.recv().await?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        Some(message)
+    }
+}
+
+/// What a peer's task is asked to deliver to its client.
+#[derive(Debug, Clone)]
+enum Outgoing {
+    /// A preformatted line, sent as is.
+    Line(String),
+    /// A chat message and its sender's color, rendered per recipient.
+    Chat(Message, Color),
+    /// A private message from the named user.
+    Whisper(String, String),
+    /// The named user entered the chat with their first session.
+    Joined(String),
+    /// The named user's last session left the chat.
+    Left(String),
+    /// A final notice, after which the peer's connection is closed.
+    Disconnect(String),
+}
 
 #[derive(Debug, Clone)]
 struct Shared {
     peers: HashMap<SocketAddr, Tx>,
     usernames: HashMap<SocketAddr, String>,
+    presence: HashMap<String, Presence>,
+    /// Every logged in connection, in the chat or in the lobby.
+    sessions: HashMap<SocketAddr, Session>,
+    /// Logged in IRC connections outside the channel. They get no chat
+    /// traffic but still count as sessions of their account, so notices
+    /// such as a ban or a takeover reach them.
+    lobby: HashMap<SocketAddr, (String, Tx)>,
+    events: broadcast::Sender<ChatEvent>,
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    connected_at: Instant,
+    last_active: Instant,
+    transport: Transport,
+}
+
+impl Session {
+    fn new(transport: Transport) -> Self {
+        Session {
+            connected_at: Instant::now(),
+            last_active: Instant::now(),
+            transport,
+        }
+    }
 }
 
 struct Peer {
-    lines: Framed<TcpStream, LinesCodec>,
+    lines: Framed<TcpStream, TelnetCodec>,
     rx: Rx,
-    color: Color,
+    settings: UserSettings,
 }
 
 impl Shared {
@@ -92,13 +262,132 @@ impl Shared {
         Shared {
             peers: HashMap::new(),
             usernames: HashMap::new(),
+            presence: HashMap::new(),
+            sessions: HashMap::new(),
+            lobby: HashMap::new(),
+            events: broadcast::channel(256).0,
+        }
+    }
+
+    /// Makes a logged in connection part of the chat. A session that was
+    /// waiting in the lobby keeps its connection time.
+    fn add_session(&mut self, addr: SocketAddr, username: &str, tx: Tx, transport: Transport) {
+        self.peers.insert(addr, tx);
+        self.usernames.insert(addr, username.to_string());
+        self.sessions
+            .entry(addr)
+            .or_insert_with(|| Session::new(transport));
+    }
+
+    /// Moves a logged in IRC connection out of the chat, or parks it there
+    /// right after logging in.
+    fn enter_lobby(&mut self, addr: SocketAddr, username: &str, tx: Tx) {
+        self.peers.remove(&addr);
+        self.usernames.remove(&addr);
+        self.lobby.insert(addr, (username.to_string(), tx));
+        self.sessions
+            .entry(addr)
+            .or_insert_with(|| Session::new(Transport::Irc));
+    }
+
+    fn remove_session(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+        self.usernames.remove(&addr);
+        self.lobby.remove(&addr);
+        self.sessions.remove(&addr);
+    }
+
+    /// The sessions of a user, for `/whois`, longest connected first.
+    fn whois_sessions(&self, username: &str) -> Vec<commands::WhoisSession> {
+        let mut sessions: Vec<commands::WhoisSession> = self
+            .sessions
+            .iter()
+            .filter_map(|(addr, session)| {
+                let room = if self.usernames.get(addr).is_some_and(|u| u == username) {
+                    match session.transport {
+                        Transport::Irc => config::get().irc_channel.clone(),
+                        _ => "main chat".to_string(),
+                    }
+                } else if self.lobby.get(addr).is_some_and(|(u, _)| u == username) {
+                    "IRC lobby".to_string()
+                } else {
+                    return None;
+                };
+                Some(commands::WhoisSession {
+                    addr: *addr,
+                    room,
+                    connected_for: session.connected_at.elapsed(),
+                    idle_for: session.last_active.elapsed(),
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.connected_for));
+        sessions
+    }
+
+    /// Tells everyone else that the user is here, unless they already were.
+    fn announce_join(&mut self, sender: SocketAddr, username: &str) {
+        tracing::info!("{} joined the chat", username);
+        if self.usernames.values().filter(|u| *u == username).count() != 1 {
+            return;
+        }
+        for (addr, tx) in &self.peers {
+            if *addr != sender {
+                let _ = tx.send(Outgoing::Joined(username.to_string()));
+            }
+        }
+        self.publish(ChatEvent::UserJoined {
+            username: username.to_string(),
+        });
+    }
+
+    /// Tells everyone that the user left, once their last session is gone.
+    async fn announce_leave(&mut self, conn: &Arc<Mutex<Connection>>, username: &str) {
+        tracing::info!("{} has left the chat", username);
+        if self.is_user_connected(username) {
+            return;
+        }
+        self.presence.remove(username);
+        if let Err(e) = database::record_logout(conn, username).await {
+            tracing::error!(
+                "failed to record the logout of {}; error = {:?}",
+                username,
+                e
+            );
         }
+        for tx in self.peers.values() {
+            let _ = tx.send(Outgoing::Left(username.to_string()));
+        }
+        self.publish(ChatEvent::UserLeft {
+            username: username.to_string(),
+        });
+    }
+
+    fn presence_mut(&mut self, username: &str) -> &mut Presence {
+        self.presence.entry(username.to_string()).or_default()
     }
 
     async fn broadcast(&mut self, sender: SocketAddr, message: &str) {
         for peer in self.peers.iter_mut() {
             if *peer.0 != sender {
-                let _ = peer.1.send(message.into());
+                let _ = peer.1.send(Outgoing::Line(message.into()));
+            }
+        }
+    }
+
+    /// Hands an event to webhooks and other subscribers, if there are any.
+    fn publish(&self, event: ChatEvent) {
+        let _ = self.events.send(event);
+    }
+
+    async fn broadcast_message(&mut self, sender: SocketAddr, message: &Message, color: Color) {
+        metrics::message_broadcast();
+        self.publish(ChatEvent::MessagePosted {
+            message: message.clone(),
+        });
+        for peer in self.peers.iter_mut() {
+            if *peer.0 != sender {
+                let _ = peer.1.send(Outgoing::Chat(message.clone(), color));
             }
         }
     }
@@ -107,32 +396,78 @@ impl Shared {
         self.usernames.values().any(|u| u == username)
     }
 
-    pub async fn get_addr_by_username(&self, username: &str) -> Option<String> {
-        for (addr, _) in self.peers.iter() {
-            if let Some(user) = self.usernames.get(addr) {
-                if *user == username {
-                    return Some(addr.to_string());
-                }
+    /// Whether the user is logged in anywhere, in the chat or not.
+    fn has_session(&self, username: &str) -> bool {
+        self.is_user_connected(username) || self.lobby.values().any(|(u, _)| u == username)
+    }
+
+    /// Returns the addresses of every session the user is connected with.
+    pub async fn get_addrs_by_username(&self, username: &str) -> Vec<SocketAddr> {
+        self.peers
+            .keys()
+            .filter(|addr| {
+                self.usernames
+                    .get(addr)
+                    .is_some_and(|user| user == username)
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Tells everyone about a rename and logs the user's sessions out, since
+    /// they are still running under the old name.
+    async fn announce_rename(&mut self, sender: SocketAddr, username: &str, new_name: &str) {
+        self.broadcast(
+            sender,
+            &format!(
+                "\n\r{} is now known as {}\n\r",
+                username.blue().bold(),
+                new_name.blue().bold()
+            ),
+        )
+        .await;
+        let notice = format!(
+            "You are now known as {}, please log in again with the new name.",
+            new_name.green().bold()
+        );
+        self.send_to_user(username, Outgoing::Disconnect(notice))
+            .await;
+    }
+
+    /// Sends `message` to every session of the user, including IRC
+    /// connections outside the channel.
+    pub async fn send_to_user(&self, username: &str, message: Outgoing) {
+        for addr in self.get_addrs_by_username(username).await {
+            if let Some(tx) = self.peers.get(&addr) {
+                let _ = tx.send(message.clone());
+            }
+        }
+        for (user, tx) in self.lobby.values() {
+            if user == username {
+                let _ = tx.send(message.clone());
             }
         }
-        None
     }
 }
 
 impl Peer {
     async fn new(
         state: Arc<Mutex<Shared>>,
-        lines: Framed<TcpStream, LinesCodec>,
+        lines: Framed<TcpStream, TelnetCodec>,
         username: String,
+        settings: UserSettings,
     ) -> io::Result<Peer> {
         let addr = lines.get_ref().peer_addr()?;
-        let (tx, rx) = mpsc::unbounded_channel();
-        state.lock().await.peers.insert(addr, tx);
-        state.lock().await.usernames.insert(addr, username.clone());
+        let (tx, rx) = channel();
+        let transport = metrics::transport_of(addr);
+        state
+            .lock()
+            .await
+            .add_session(addr, &username, tx, transport);
         Ok(Peer {
             lines,
             rx,
-            color: Color::Green,
+            settings,
         })
     }
 }
@@ -143,142 +478,157 @@ async fn process(
     stream: TcpStream,
     addr: SocketAddr,
 ) -> Result<(), Box<dyn Error>> {
-    let mut lines = Framed::new(stream, LinesCodec::new());
+    let mut lines = Framed::new(stream, TelnetCodec::new());
+    if config::get().telnet_negotiation {
+        telnet::negotiate(&mut lines).await?;
+    }
+
+    let username = match login::login(&state, &conn, &mut lines, addr).await? {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    let username = username.as_str();
+    logging::logged_in(username);
 
     lines
-        .send(
-            "Please enter 'register' or 'login': \n\rregister username password:"
-                .blue()
-                .to_string(),
-        )
+        .send("\n\rWelcome to the chat!".green().to_string())
         .await?;
 
-    let login = match lines.next().await {
-        Some(Ok(line)) => line,
-        _ => {
-            tracing::error!(
-                "Failed to get login information from {}. Client disconnected.",
-                addr
-            );
-            return Ok(());
-        }
-    };
-    let login_parts = login.split(' ').collect::<Vec<&str>>();
-
-    if login_parts.len() < 3 {
-        lines
-            .send("Invalid input format. Please enter 'register' or 'login': \n\rregister username password:".red().to_string())
-            .await?;
-        return Ok(());
-    }
+    database::record_login(&conn, username).await?;
 
-    let register_or_login = login_parts[0];
-    let username = login_parts[1];
-    let password = login_parts[2];
-
-    if register_or_login == "register" {
-        match database::register_user(&conn, username, password).await {
-            Ok(_) => {
-                tracing::info!("registered user {}", username);
-                lines
-                    .send(format!(
-                        "Registration successful, welcome {}!",
-                        username.green().bold()
-                    ))
-                    .await?;
-            }
-            Err(e) => {
-                lines.send(format!("Registration failed: {:?}", e)).await?;
-                return Ok(());
-            }
-        }
-    } else if register_or_login == "login" {
-        let authenticated = database::authenticate_user(&conn, username, password).await?;
-        if !authenticated {
-            lines
-                .send("Authentication failed, please try again.")
-                .await?;
-            return Ok(());
-        }
-        if state.lock().await.is_user_connected(username) {
-            lines
-                .send("User already connected, please try again.")
-                .await?;
-            return Ok(());
-        }
-        if database::get_user_role(&conn, username).await? == "banned" {
-            lines
-                .send("You are banned, please try again later.")
-                .await?;
-            return Ok(());
-        }
-    } else {
+    let unseen_mentions = database::count_unseen_mentions(&conn, username).await?;
+    if unseen_mentions > 0 {
         lines
-            .send("Invalid command, use 'register' or 'login' followed by username and password.")
+            .send(format!(
+                "You were mentioned {} time(s) while away, use /mentions to read them.",
+                unseen_mentions.to_string().yellow().bold()
+            ))
             .await?;
-        return Ok(());
     }
 
-    lines
-        .send("\n\rWelcome to the chat!".green().to_string())
-        .await?;
+    let settings = database::get_user_settings(&conn, username)
+        .await
+        .unwrap_or_default();
+    let mut peer = Peer::new(state.clone(), lines, username.to_string(), settings).await?;
 
-    let mut peer = Peer::new(state.clone(), lines, username.to_string()).await?;
+    state.lock().await.announce_join(addr, username);
 
-    {
-        let mut state = state.lock().await;
-        tracing::info!("{} joined the chat", username);
-        state
-            .broadcast(
-                addr,
-                format!("\n\r-> User joined: {0}\n\r", username.blue().bold(),).as_str(),
-            )
-            .await;
-    }
+    let auto_away_after = config::get().auto_away_after;
+    let idle_timeout = config::get().idle_timeout;
+    let mut idle_check = tokio::time::interval(
+        [auto_away_after, idle_timeout]
+            .into_iter()
+            .flatten()
+            .fold(Duration::from_secs(30), Duration::min),
+    );
 
     loop {
         tokio::select! {
-            Some(msg) = peer.rx.recv() => {
-                peer.lines.send(&msg).await?;
-                if msg == "You have been banned.".red().to_string() {
+            _ = idle_check.tick() => {
+                let mut state = state.lock().await;
+                let idle = state.sessions.get(&addr).map(|s| s.last_active.elapsed()).unwrap_or_default();
+                if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                    tracing::info!("disconnecting {} after being idle for {:?}", username, idle);
+                    peer.lines.send("Disconnected after being idle for too long.".red().to_string()).await?;
                     break;
                 }
+                if auto_away_after.is_some_and(|after| idle >= after) {
+                    let presence = state.presence_mut(username);
+                    if presence.state == presence::PresenceState::Online {
+                        presence.set_away(None, true);
+                        tracing::info!("{} is now away after being idle", username);
+                        peer.lines.send("You have been marked as away after being idle.".yellow().to_string()).await?;
+                    }
+                }
             }
+            Some(msg) = peer.rx.recv() => match msg {
+                Outgoing::Line(line) => {
+                    peer.lines.send(&line).await?;
+                }
+                Outgoing::Whisper(sender, text) => {
+                    let bell = if peer.settings.notifications { "\x07" } else { "" };
+                    peer.lines.send(format!("{}{}(whisper): {}", bell, sender.green().bold(), text)).await?;
+                }
+                Outgoing::Joined(user) => {
+                    peer.lines.send(format!("\n\r-> User joined: {0}\n\r", user.blue().bold())).await?;
+                }
+                Outgoing::Left(user) => {
+                    peer.lines.send(format!("\n\r<- User left: {0}\n\r", user.red().bold())).await?;
+                }
+                Outgoing::Disconnect(notice) => {
+                    peer.lines.send(&notice).await?;
+                    break;
+                }
+                Outgoing::Chat(message, color) => {
+                    peer.lines.send(commands::render_message(&message, color, username, &peer.settings)).await?;
+                    if message.mentions().iter().any(|m| m == username) {
+                        database::mark_mentions_seen(&conn, username, message.id).await?;
+                    }
+                }
+            },
             result = peer.lines.next() => match result {
                 Some(Ok(msg)) => {
+                    telnet::answer(&mut peer.lines).await?;
+                    let width = peer.lines.codec().window_size().map(|(width, _)| width);
                     let mut state = state.lock().await;
-                    match msg.trim().split(' ').collect::<Vec<&str>>()[0] {
+                    if let Some(session) = state.sessions.get_mut(&addr) {
+                        session.last_active = Instant::now();
+                    }
+                    if state.presence_mut(username).auto_away {
+                        state.presence_mut(username).set_back();
+                        peer.lines.send("Welcome back, you are no longer away.".green().to_string()).await?;
+                    }
+                    let command = msg.trim().split(' ').collect::<Vec<&str>>()[0];
+                    let mut is_command = true;
+                    match command {
                         "/listusers" => {
                             let users = database::get_all_users(&conn).await.unwrap_or_default();
 
                             let mut table = Table::new();
-                            table.add_row(row!["Username", "Status", "Role"]);
+                            table.add_row(row!["Username", "Status", "Role", "Message"]);
 
                             for user in users {
                                 let is_connected = state.is_user_connected(&user);
-                                let status = if is_connected { "Online".green() } else { "Offline".red() };
+                                let presence = state.presence.get(&user).cloned().unwrap_or_default();
+                                let status = if is_connected { presence.label() } else { "Offline".red() };
                                 let role = database::get_user_role(&conn, &user).await.unwrap_or_default();
-                                table.add_row(row![user, status, role]);
+                                let message = presence.status.or(presence.away_message).unwrap_or_default();
+                                table.add_row(row![user, status, role, message]);
                             }
 
-                            let mut response = Vec::new();
-                            table.print(&mut response).unwrap();
-                            let response = String::from_utf8(response).unwrap();
-
-                            peer.lines.send(response).await?;
+                            peer.lines.send(commands::render_table(&mut table, width)).await?;
                             tracing::info!("{} requested a list of users", username);
                         }
+                        "/ping" => {
+                            peer.lines.send(format!("pong {}", chrono::Utc::now().to_rfc3339())).await?;
+                        }
+                        "/whois" => {
+                            match msg.split_whitespace().nth(1) {
+                                Some(target) => match database::get_user_profile(&conn, target).await? {
+                                    Some(profile) => {
+                                        let sessions = state.whois_sessions(target);
+                                        let is_admin = database::get_user_role(&conn, username).await? == "admin";
+                                        let presence = state.presence.get(target).cloned().unwrap_or_default();
+                                        peer.lines.send(commands::format_whois(&profile, &presence, &sessions, is_admin, &peer.settings, width)).await?;
+                                    }
+                                    None => {
+                                        peer.lines.send("User not found.".red().to_string()).await?;
+                                    }
+                                },
+                                None => {
+                                    peer.lines.send("Invalid command format. Use /whois <username>").await?;
+                                }
+                            }
+                        }
                         "/whisper" => {
                             let mut parts = msg.splitn(3, ' ');
                             if let Some(_pm_cmd) = parts.next() {
                                 if let Some(target_username) = parts.next() {
                                     if let Some(private_message) = parts.next() {
-                                        if let Some(target_addr_str) = state.get_addr_by_username(target_username).await {
-                                            if let Ok(target_addr) = target_addr_str.parse::<SocketAddr>() {
-                                                if let Some(target_tx) = state.peers.get(&target_addr) {
-                                                    let msg = format!("{}(whisper): {}", username.green().bold(), private_message);
-                                                    target_tx.send(msg).unwrap();
-                                                }
+                                        if state.is_user_connected(target_username) {
+                                            state.send_to_user(target_username, Outgoing::Whisper(username.to_string(), private_message.to_string())).await;
+                                            if let Some(reply) = state.presence.get(target_username).and_then(|p| p.auto_reply(target_username)) {
+                                                peer.lines.send(format!("{} {}", "(auto-reply)".bright_black(), reply)).await?;
                                             }
                                         } else {
                                             peer.lines.send("User not found or not connected.").await?;
@@ -293,18 +643,20 @@ async fn process(
                         }
                         "/history" => {
                             let mut args = msg.split_whitespace().skip(1);
+                            let colors = database::get_user_colors(&conn).await.unwrap_or_default();
+                            let reactions = database::get_reaction_counts(&conn).await.unwrap_or_default();
                             match args.next() {
                                 Some("all") => {
                                     let messages = database::get_all_messages(&conn).await.unwrap_or_default();
-                                    peer.lines.send(commands::format_message_history("all users", &messages)).await?;
+                                    peer.lines.send(commands::format_message_history("all users", &messages, &colors, &reactions, &peer.settings)).await?;
                                 }
                                 Some(username) => {
                                     let messages = database::get_messages_by_user(&conn, username).await.unwrap_or_default();
-                                    peer.lines.send(commands::format_message_history(username, &messages)).await?;
+                                    peer.lines.send(commands::format_message_history(username, &messages, &colors, &reactions, &peer.settings)).await?;
                                 }
                                 None => {
                                     let messages = database::get_messages_by_user(&conn, username).await.unwrap_or_default();
-                                    peer.lines.send(commands::format_message_history(username, &messages)).await?;
+                                    peer.lines.send(commands::format_message_history(username, &messages, &colors, &reactions, &peer.settings)).await?;
                                 }
                             }
                         }
@@ -314,8 +666,10 @@ async fn process(
                                 if let Some(old_password) = parts.next() {
                                     if let Some(new_password) = parts.next() {
                                         if database::authenticate_user(&conn, username, old_password).await? {
-                                            database::update_user_password(&conn, username, new_password).await?;
-                                            peer.lines.send("Password updated successfully.".green().to_string()).await?;
+                                            match database::update_user_password(&conn, username, new_password).await {
+                                                Ok(()) => peer.lines.send("Password updated successfully.".green().to_string()).await?,
+                                                Err(e) => peer.lines.send(e.to_string().red().to_string()).await?,
+                                            }
                                         } else {
                                             peer.lines.send("Incorrect password. Please try again.".red().to_string()).await?;
                                         }
@@ -327,13 +681,104 @@ async fn process(
                                 }
                             }
                         }
+                        "/deleteaccount" => {
+                            match commands::argument_text(&msg) {
+                                Some(password) => {
+                                    if database::authenticate_user(&conn, username, &password).await? {
+                                        database::delete_account(&conn, username, config::get().delete_account_messages).await?;
+                                        state.send_to_user(username, Outgoing::Disconnect("Your account has been deleted, goodbye.".yellow().to_string())).await;
+                                    } else {
+                                        peer.lines.send("Incorrect password. Please try again.".red().to_string()).await?;
+                                    }
+                                }
+                                None => {
+                                    peer.lines.send("Invalid command format. Use /deleteaccount <password>").await?;
+                                }
+                            }
+                        }
+                        "/rename" => {
+                            match msg.split_whitespace().nth(1) {
+                                Some(new_name) => {
+                                    let is_admin = database::get_user_role(&conn, username).await? == "admin";
+                                    if config::get().rename_approval && !is_admin {
+                                        match database::request_rename(&conn, username, new_name).await {
+                                            Ok(()) => {
+                                                peer.lines.send(format!("Rename to {} requested, an admin has to approve it.", new_name.green())).await?;
+                                                let online: HashSet<String> = state.usernames.values().cloned().collect();
+                                                for user in online {
+                                                    if database::get_user_role(&conn, &user).await.unwrap_or_default() == "admin" {
+                                                        let notice = format!("{} asked to be renamed to {}, see /renames.", username.blue(), new_name.blue());
+                                                        state.send_to_user(&user, Outgoing::Line(notice)).await;
+                                                    }
+                                                }
+                                            }
+                                            Err(e) => peer.lines.send(e.to_string().red().to_string()).await?,
+                                        }
+                                    } else {
+                                        match database::rename_user(&conn, username, new_name).await {
+                                            Ok(()) => state.announce_rename(addr, username, new_name).await,
+                                            Err(e) => peer.lines.send(e.to_string().red().to_string()).await?,
+                                        }
+                                    }
+                                }
+                                None => {
+                                    peer.lines.send("Invalid command format. Use /rename <new_name>").await?;
+                                }
+                            }
+                        }
+                        "/renames" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                let mut parts = msg.split_whitespace().skip(1);
+                                match (parts.next(), parts.next()) {
+                                    (None, _) => {
+                                        let requests = database::get_rename_requests(&conn).await?;
+                                        if requests.is_empty() {
+                                            peer.lines.send("No pending rename requests.").await?;
+                                        } else {
+                                            peer.lines.send(commands::format_rename_requests(&requests, &peer.settings, width)).await?;
+                                        }
+                                    }
+                                    (Some("approve"), Some(user)) => match database::take_rename_request(&conn, user).await? {
+                                        Some(new_name) => match database::rename_user(&conn, user, &new_name).await {
+                                            Ok(()) => {
+                                                state.announce_rename(addr, user, &new_name).await;
+                                                peer.lines.send(format!("{} has been renamed to {}.", user.green(), new_name.green())).await?;
+                                            }
+                                            Err(e) => peer.lines.send(e.to_string().red().to_string()).await?,
+                                        },
+                                        None => peer.lines.send(format!("No rename request from {}.", user)).await?,
+                                    },
+                                    (Some("deny"), Some(user)) => match database::take_rename_request(&conn, user).await? {
+                                        Some(new_name) => {
+                                            let notice = format!("Your request to be renamed to {} was denied.", new_name);
+                                            state.send_to_user(user, Outgoing::Line(notice.yellow().to_string())).await;
+                                            peer.lines.send(format!("Denied the rename of {}.", user.green())).await?;
+                                        }
+                                        None => peer.lines.send(format!("No rename request from {}.", user)).await?,
+                                    },
+                                    _ => {
+                                        peer.lines.send("Invalid command format. Use /renames [approve|deny <username>]").await?;
+                                    }
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/exportme" => {
+                            match database::export_account(&conn, username).await? {
+                                Some(export) => peer.lines.send(serde_json::to_string_pretty(&export)?).await?,
+                                None => peer.lines.send("Your account could not be found.".red().to_string()).await?,
+                            }
+                            tracing::info!("{} exported their data", username);
+                        }
                         "/color" => {
                             let mut parts = msg.split_whitespace().skip(1);
                             if let Some(color_name) = parts.next() {
                                 match Color::from_str(color_name) {
                                     Ok(color) => {
-                                        peer.color = color;
-                                        peer.lines.send(format!("Text color changed to {}.", color_name.green())).await?;
+                                        peer.settings.color = color_name.to_lowercase();
+                                        database::save_user_settings(&conn, username, &peer.settings).await?;
+                                        peer.lines.send(format!("Text color changed to {}.", color_name.color(color))).await?;
                                     }
                                     Err(_) => {
                                         peer.lines.send("Invalid color. Please provide a valid color name.".red().to_string()).await?;
@@ -343,6 +788,182 @@ async fn process(
                                 peer.lines.send("Invalid command format. Use /color <color_name>").await?;
                             }
                         }
+                        "/reply" => {
+                            let mut parts = msg.splitn(3, ' ').skip(1);
+                            match (parts.next().and_then(commands::parse_message_id), parts.next()) {
+                                (Some(parent_id), Some(content)) => {
+                                    if database::get_user_role(&conn, username).await? == "muted" {
+                                        peer.lines.send("You are muted.".red().to_string()).await?;
+                                    } else if let Some(parent) = database::get_message(&conn, parent_id).await? {
+                                        let mut reply = Message::from_input(username.to_string(), content.to_string()).reply_to(parent_id);
+                                        let id = database::store_message(&conn, &mut reply).await?;
+                                        database::store_mentions(&conn, id, &reply.mentions()).await?;
+                                        let parent_color = database::get_user_settings(&conn, &parent.sender).await.unwrap_or_default().color();
+                                        state.broadcast(addr, &commands::format_quote(&parent, parent_color)).await;
+                                        state.broadcast_message(addr, &reply, peer.settings.color()).await;
+                                        if let (Some(id), true) = (reply.id, peer.settings.show_ids) {
+                                            peer.lines.send(format!("#{}", id).bright_black().to_string()).await?;
+                                        }
+                                    } else {
+                                        peer.lines.send("Message not found.".red().to_string()).await?;
+                                    }
+                                }
+                                _ => {
+                                    peer.lines.send("Invalid command format. Use /reply <id> <text>").await?;
+                                }
+                            }
+                        }
+                        "/thread" => {
+                            match msg.split_whitespace().nth(1).and_then(commands::parse_message_id) {
+                                Some(id) => {
+                                    let thread = database::get_thread(&conn, id).await?;
+                                    if thread.is_empty() {
+                                        peer.lines.send("Message not found.".red().to_string()).await?;
+                                    } else {
+                                        let colors = database::get_user_colors(&conn).await.unwrap_or_default();
+                                        peer.lines.send(commands::format_thread(&thread, &colors, &peer.settings)).await?;
+                                    }
+                                }
+                                None => {
+                                    peer.lines.send("Invalid command format. Use /thread <id>").await?;
+                                }
+                            }
+                        }
+                        "/react" => {
+                            let mut parts = msg.split_whitespace().skip(1);
+                            match (parts.next().and_then(commands::parse_message_id), parts.next()) {
+                                (Some(id), Some(input)) => {
+                                    if database::get_user_role(&conn, username).await? == "muted" {
+                                        // Reactions are announced to everyone, so they are muted too.
+                                        peer.lines.send("You are muted.".red().to_string()).await?;
+                                    } else if let Some(emoji) = emoji::parse_reaction(input) {
+                                        if let Some(target) = database::get_message(&conn, id).await? {
+                                            let added = database::toggle_reaction(&conn, id, username, &emoji).await?;
+                                            let action = if added { "reacted" } else { "removed their reaction" };
+                                            let notice = format!("{} {} {} to #{} ({}: {})", username.color(peer.settings.color()), action, emoji, id, target.sender, target.preview(30));
+                                            state.broadcast(addr, &notice.bright_black().to_string()).await;
+                                            peer.lines.send(format!("{} {} #{}", if added { "Reacted" } else { "Removed" }, emoji, id).green().to_string()).await?;
+                                        } else {
+                                            peer.lines.send("Message not found.".red().to_string()).await?;
+                                        }
+                                    } else {
+                                        let shortcodes: Vec<String> = emoji::shortcodes().map(|(name, emoji)| format!(":{}: {}", name, emoji)).collect();
+                                        peer.lines.send(format!("{}\n\rKnown shortcodes: {}", "Unknown emoji.".red(), shortcodes.join("  "))).await?;
+                                    }
+                                }
+                                _ => {
+                                    peer.lines.send("Invalid command format. Use /react <id> <emoji or :shortcode:>").await?;
+                                }
+                            }
+                        }
+                        "/mentions" => {
+                            let mentions = database::get_mentions(&conn, username, 20).await?;
+                            let colors = database::get_user_colors(&conn).await.unwrap_or_default();
+                            let reactions = database::get_reaction_counts(&conn).await.unwrap_or_default();
+                            peer.lines.send(commands::format_mentions(&mentions, &colors, &reactions, &peer.settings)).await?;
+                            database::mark_mentions_seen(&conn, username, None).await?;
+                        }
+                        "/edit" => {
+                            let mut parts = msg.splitn(3, ' ').skip(1);
+                            match (parts.next().and_then(commands::parse_message_id), parts.next()) {
+                                (Some(id), Some(content)) => {
+                                    if database::get_user_role(&conn, username).await? == "muted" {
+                                        peer.lines.send("You are muted.".red().to_string()).await?;
+                                    } else if let Some(reason) = commands::check_message_access(&conn, username, id).await? {
+                                        peer.lines.send(reason.red().to_string()).await?;
+                                    } else {
+                                        database::edit_message(&conn, id, username, content).await?;
+                                        tracing::info!("{} edited message #{}", username, id);
+                                        let notice = format!("{} edited #{}: {} {}", username.color(peer.settings.color()), id, content, "(edited)".bright_black());
+                                        state.broadcast(addr, &notice).await;
+                                        peer.lines.send(format!("Message #{} edited.", id).green().to_string()).await?;
+                                    }
+                                }
+                                _ => {
+                                    peer.lines.send("Invalid command format. Use /edit <id> <text>").await?;
+                                }
+                            }
+                        }
+                        "/delete" => {
+                            match msg.split_whitespace().nth(1).and_then(commands::parse_message_id) {
+                                Some(id) => {
+                                    if let Some(reason) = commands::check_message_access(&conn, username, id).await? {
+                                        peer.lines.send(reason.red().to_string()).await?;
+                                    } else {
+                                        database::delete_message(&conn, id, username).await?;
+                                        tracing::info!("{} deleted message #{}", username, id);
+                                        let notice = format!("{} deleted message #{}", username.color(peer.settings.color()), id);
+                                        state.broadcast(addr, &notice.bright_black().to_string()).await;
+                                        peer.lines.send(format!("Message #{} deleted.", id).green().to_string()).await?;
+                                    }
+                                }
+                                None => {
+                                    peer.lines.send("Invalid command format. Use /delete <id>").await?;
+                                }
+                            }
+                        }
+                        "/away" => {
+                            let message = commands::argument_text(&msg);
+                            state.presence_mut(username).set_away(message, false);
+                            peer.lines.send("You are now marked as away.".yellow().to_string()).await?;
+                        }
+                        "/busy" => {
+                            state.presence_mut(username).set_busy();
+                            peer.lines.send("You are now marked as busy.".magenta().to_string()).await?;
+                        }
+                        "/back" => {
+                            state.presence_mut(username).set_back();
+                            peer.lines.send("You are now marked as online.".green().to_string()).await?;
+                        }
+                        "/status" => {
+                            let status = commands::argument_text(&msg);
+                            let cleared = status.is_none();
+                            state.presence_mut(username).status = status;
+                            peer.lines.send(if cleared { "Status cleared." } else { "Status updated." }.green().to_string()).await?;
+                        }
+                        "/timezone" => {
+                            let mut parts = msg.split_whitespace().skip(1);
+                            if let Some(timezone) = parts.next() {
+                                if settings::parse_timezone(timezone).is_some() {
+                                    peer.settings.timezone = timezone.to_string();
+                                    database::save_user_settings(&conn, username, &peer.settings).await?;
+                                    peer.lines.send(format!("Timezone changed to {}.", timezone.green())).await?;
+                                } else {
+                                    peer.lines.send("Invalid timezone. Use an offset like UTC, +02:00 or -5.".red().to_string()).await?;
+                                }
+                            } else {
+                                peer.lines.send("Invalid command format. Use /timezone <offset>").await?;
+                            }
+                        }
+                        "/notify" => {
+                            let mut parts = msg.split_whitespace().skip(1);
+                            match parts.next() {
+                                Some(value @ ("on" | "off")) => {
+                                    peer.settings.notifications = value == "on";
+                                    database::save_user_settings(&conn, username, &peer.settings).await?;
+                                    peer.lines.send(format!("Notifications turned {}.", if peer.settings.notifications { "on".green() } else { "off".red() })).await?;
+                                }
+                                _ => {
+                                    peer.lines.send("Invalid command format. Use /notify <on|off>").await?;
+                                }
+                            }
+                        }
+                        "/ids" => {
+                            let mut parts = msg.split_whitespace().skip(1);
+                            match parts.next() {
+                                Some(value @ ("on" | "off")) => {
+                                    peer.settings.show_ids = value == "on";
+                                    database::save_user_settings(&conn, username, &peer.settings).await?;
+                                    peer.lines.send(format!("Message ids turned {}.", if peer.settings.show_ids { "on".green() } else { "off".red() })).await?;
+                                }
+                                _ => {
+                                    peer.lines.send("Invalid command format. Use /ids <on|off>").await?;
+                                }
+                            }
+                        }
+                        "/settings" => {
+                            peer.lines.send(commands::format_settings(&peer.settings, width)).await?;
+                        }
                         "/admin" => {
                             let mut password = msg.split_whitespace().skip(1);
 
@@ -361,19 +982,222 @@ async fn process(
                                 peer.lines.send("Invalid command format. Use /admin <password>").await?;
                             }
                         }
-                        "/ban" => {
+                        "/token" => {
+                            let is_admin = database::get_user_role(&conn, username).await? == "admin";
+                            let mut parts = msg.split_whitespace().skip(1);
+                            match parts.next() {
+                                Some("create") => match commands::parse_token_request(parts) {
+                                    Ok(request) => {
+                                        let owner = request.user.clone().unwrap_or_else(|| username.to_string());
+                                        let allowed = if owner == username {
+                                            true
+                                        } else {
+                                            is_admin && database::get_user_profile(&conn, &owner).await?.is_some_and(|profile| profile.bot)
+                                        };
+                                        if allowed {
+                                            let (id, token) = database::create_token(&conn, &owner, &request.name, &request.scopes, request.expires_at).await?;
+                                            tracing::info!("{} created token {} for {}", username, id, owner);
+                                            peer.lines.send(format!("Token #{} for {}: {}", id, owner.green(), token.bold())).await?;
+                                            peer.lines.send("Store it now, it cannot be shown again.".yellow().to_string()).await?;
+                                        } else {
+                                            peer.lines.send("Admins can create tokens for bot accounts only.".red().to_string()).await?;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        peer.lines.send(e.red().to_string()).await?;
+                                        peer.lines.send("Use /token create <name> [scopes=chat,read,moderate] [expires=<days>] [user=<bot>]").await?;
+                                    }
+                                },
+                                Some("list") => {
+                                    let owner = if is_admin && parts.next() == Some("all") { None } else { Some(username) };
+                                    let tokens = database::get_tokens(&conn, owner).await?;
+                                    if tokens.is_empty() {
+                                        peer.lines.send("No tokens.").await?;
+                                    } else {
+                                        peer.lines.send(commands::format_tokens(&tokens, &peer.settings, width)).await?;
+                                    }
+                                }
+                                Some("revoke") => match parts.next().and_then(commands::parse_message_id) {
+                                    Some(id) => {
+                                        let owner = if is_admin { None } else { Some(username) };
+                                        if database::revoke_token(&conn, id, owner).await? {
+                                            peer.lines.send(format!("Token #{} has been revoked.", id)).await?;
+                                        } else {
+                                            peer.lines.send(format!("Token #{} not found.", id).red().to_string()).await?;
+                                        }
+                                    }
+                                    None => {
+                                        peer.lines.send("Invalid command format. Use /token revoke <id>").await?;
+                                    }
+                                },
+                                _ => {
+                                    peer.lines.send("Invalid command format. Use /token create|list|revoke").await?;
+                                }
+                            }
+                        }
+                        "/bot" => {
                             if database::get_user_role(&conn, username).await? == "admin" {
                                 let mut parts = msg.split_whitespace().skip(1);
-                                if let Some(username) = parts.next() {
-                                    database::change_role(&conn, username, "banned").await?;
-                                    if let Some(addr) = state.get_addr_by_username(username).await {
-                                        if let Ok(addr) = addr.parse::<SocketAddr>() {
-                                            if let Some(tx) = state.peers.get(&addr) {
-                                                tx.send("You have been banned.".red().to_string()).unwrap();
+                                match (parts.next(), parts.next()) {
+                                    (Some("create"), Some(name)) => match database::create_bot(&conn, name).await {
+                                        Ok(()) => {
+                                            peer.lines.send(format!("Bot {} created, give it a token with /token create <name> user={}.", name.green(), name)).await?;
+                                        }
+                                        Err(e) => peer.lines.send(e.to_string().red().to_string()).await?,
+                                    },
+                                    _ => {
+                                        peer.lines.send("Invalid command format. Use /bot create <name>").await?;
+                                    }
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/webhooks" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                let mut parts = msg.split_whitespace().skip(1);
+                                match (parts.next(), parts.next()) {
+                                    (Some("create"), Some(name)) => {
+                                        let rate_limit = match parts.next().map(|arg| arg.strip_prefix("limit=").and_then(|n| n.parse::<u32>().ok()).filter(|n| *n > 0)) {
+                                            Some(Some(limit)) => Some(limit),
+                                            Some(None) => None,
+                                            None => Some(config::get().incoming_webhook_rate_limit),
+                                        };
+                                        let sender = match database::get_user_profile(&conn, name).await? {
+                                            Some(profile) if profile.bot => Ok(()),
+                                            Some(_) => Err(format!("{} is not a bot account.", name)),
+                                            None => database::create_bot(&conn, name).await.map_err(|e| e.to_string()),
+                                        };
+                                        match (rate_limit, sender) {
+                                            (Some(rate_limit), Ok(())) => {
+                                                let (id, secret) = database::create_incoming_webhook(&conn, name, rate_limit, username).await?;
+                                                tracing::info!("{} created incoming webhook {} for {}", username, id, name);
+                                                let url = match config::get().api_addr {
+                                                    Some(addr) => format!("http://{}/hooks/{}", addr, secret),
+                                                    None => format!("/hooks/{} (the HTTP API is disabled)", secret),
+                                                };
+                                                peer.lines.send(format!("Webhook #{} posts as {}: {}", id, name.green(), url.bold())).await?;
+                                                peer.lines.send("Store the URL now, it cannot be shown again.".yellow().to_string()).await?;
+                                            }
+                                            (None, _) => {
+                                                peer.lines.send("Invalid limit, use limit=<messages per minute>.".red().to_string()).await?;
                                             }
+                                            (_, Err(e)) => peer.lines.send(e.red().to_string()).await?,
+                                        }
+                                    }
+                                    (Some("list"), None) | (None, None) => {
+                                        let hooks = database::get_incoming_webhooks(&conn).await?;
+                                        if hooks.is_empty() {
+                                            peer.lines.send("No incoming webhooks.").await?;
+                                        } else {
+                                            peer.lines.send(commands::format_incoming_webhooks(&hooks, &peer.settings, width)).await?;
+                                        }
+                                    }
+                                    (Some("revoke"), Some(id)) => match commands::parse_message_id(id) {
+                                        Some(id) if database::revoke_incoming_webhook(&conn, id).await? => {
+                                            peer.lines.send(format!("Webhook #{} has been revoked.", id)).await?;
+                                        }
+                                        _ => peer.lines.send(format!("Webhook {} not found.", id).red().to_string()).await?,
+                                    },
+                                    _ => {
+                                        peer.lines.send("Invalid command format. Use /webhooks [create <name> [limit=<per minute>]|list|revoke <id>]").await?;
+                                    }
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/resetpw" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                if let Some(target) = msg.split_whitespace().nth(1) {
+                                    let password = validation::temporary_password();
+                                    if database::set_temporary_password(&conn, target, &password).await? {
+                                        tracing::info!("{} reset the password of {}", username, target);
+                                        state.send_to_user(target, Outgoing::Disconnect("Your password has been reset by an admin.".yellow().to_string())).await;
+                                        peer.lines.send(format!("Temporary password for {}: {}", target.green(), password.bold())).await?;
+                                        peer.lines.send("It has to be changed at the next login.").await?;
+                                    } else {
+                                        peer.lines.send(format!("User {} not found.", target)).await?;
+                                    }
+                                } else {
+                                    peer.lines.send("Invalid command format. Use /resetpw <username>").await?;
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/deluser" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                match msg.split_whitespace().nth(1) {
+                                    Some(target) if target == username => {
+                                        peer.lines.send("Use /deleteaccount to delete your own account.").await?;
+                                    }
+                                    Some(target) => {
+                                        if database::get_user_profile(&conn, target).await?.is_some() {
+                                            database::delete_account(&conn, target, config::get().delete_account_messages).await?;
+                                            tracing::info!("{} deleted the account of {}", username, target);
+                                            state.send_to_user(target, Outgoing::Disconnect("Your account has been deleted by an admin.".red().to_string())).await;
+                                            peer.lines.send(format!("{} has been deleted.", target.green())).await?;
+                                        } else {
+                                            peer.lines.send(format!("User {} not found.", target)).await?;
+                                        }
+                                    }
+                                    None => {
+                                        peer.lines.send("Invalid command format. Use /deluser <username>").await?;
+                                    }
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/lock" | "/unlock" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                let lock = msg.trim().starts_with("/lock");
+                                if let Some(target) = msg.split_whitespace().nth(1) {
+                                    if database::set_locked(&conn, target, lock).await? {
+                                        if lock {
+                                            state.send_to_user(target, Outgoing::Disconnect("Your account has been locked.".red().to_string())).await;
+                                            peer.lines.send(format!("{} has been locked.", target.green())).await?;
+                                        } else {
+                                            peer.lines.send(format!("{} has been unlocked.", target.green())).await?;
                                         }
+                                    } else {
+                                        peer.lines.send(format!("User {} not found.", target)).await?;
+                                    }
+                                } else if lock {
+                                    peer.lines.send("Invalid command format. Use /lock <username>").await?;
+                                } else {
+                                    peer.lines.send("Invalid command format. Use /unlock <username>").await?;
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/users" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                match commands::parse_user_filter(msg.split_whitespace().skip(1)) {
+                                    Ok((filter, page)) => {
+                                        const PAGE_SIZE: i64 = 20;
+                                        let (users, total) = database::find_users(&conn, &filter, PAGE_SIZE, (page - 1).saturating_mul(PAGE_SIZE)).await?;
+                                        peer.lines.send(commands::format_users(&users, total, page, PAGE_SIZE, &peer.settings, width)).await?;
                                     }
-                                    peer.lines.send(format!("{} has been banned.", username.green())).await?;
+                                    Err(e) => {
+                                        peer.lines.send(e.red().to_string()).await?;
+                                        peer.lines.send("Use /users [role=<role>] [seen=<days>] [inactive=<days>] [after=<date>] [before=<date>] [page=<n>]").await?;
+                                    }
+                                }
+                            } else {
+                                peer.lines.send("You are not an admin.".red().to_string()).await?;
+                            }
+                        }
+                        "/ban" => {
+                            if database::get_user_role(&conn, username).await? == "admin" {
+                                let mut parts = msg.split_whitespace().skip(1);
+                                if let Some(target) = parts.next() {
+                                    database::change_role(&conn, target, "banned").await?;
+                                    state.send_to_user(target, Outgoing::Disconnect("You have been banned.".red().to_string())).await;
+                                    state.publish(ChatEvent::UserBanned { username: target.to_string(), by: username.to_string() });
+                                    peer.lines.send(format!("{} has been banned.", target.green())).await?;
                                 } else {
                                     peer.lines.send("Invalid command format. Use /ban <username>").await?;
                                 }
@@ -421,37 +1245,71 @@ async fn process(
                             }
                         }
                         "/help" => {
-                            let mut response = Vec::new();
                             let mut table = Table::new();
                             table.add_row(row!["Command", "Description"]);
                             table.add_row(row!["/listusers", "List all users"]);
+                            table.add_row(row!["/whois <username>", "Show details about a user"]);
                             table.add_row(row!["/history [blank, username, all]", "Show msg history"]);
                             table.add_row(row!["/whisper <username> <message>", "Send a private message to a user"]);
+                            table.add_row(row!["/reply <id> <text>", "Reply to a message"]);
+                            table.add_row(row!["/thread <id>", "Show the thread a message belongs to"]);
+                            table.add_row(row!["/react <id> <emoji or :shortcode:>", "React to a message, again to remove"]);
+                            table.add_row(row!["/mentions", "List recent messages mentioning you"]);
+                            table.add_row(row!["/edit <id> <text>", "Edit one of your messages"]);
+                            table.add_row(row!["/delete <id>", "Delete one of your messages"]);
                             table.add_row(row!["/changepw <old_password> <new_password>", "Change your password"]);
+                            table.add_row(row!["/deleteaccount <password>", "Delete your account"]);
+                            table.add_row(row!["/rename <new_name>", "Change your username"]);
+                            table.add_row(row!["/exportme", "Download everything stored about you as JSON"]);
+                            table.add_row(row!["/token create|list|revoke", "Manage your API tokens"]);
                             table.add_row(row!["/color <color_name>", "Change your username color"]);
+                            table.add_row(row!["/away [message]", "Mark yourself as away"]);
+                            table.add_row(row!["/busy", "Mark yourself as busy"]);
+                            table.add_row(row!["/back", "Mark yourself as online again"]);
+                            table.add_row(row!["/status [text]", "Set or clear your status message"]);
+                            table.add_row(row!["/timezone <offset>", "Set the timezone used for history"]);
+                            table.add_row(row!["/notify <on|off>", "Toggle the bell on whispers"]);
+                            table.add_row(row!["/ids <on|off>", "Show message ids on incoming messages"]);
+                            table.add_row(row!["/settings", "Show your saved settings"]);
                             table.add_row(row!["/admin <password>", "Become an admin"]);
+                            table.add_row(row!["/renames [approve|deny <username>]", "Review pending rename requests"]);
+                            table.add_row(row!["/bot create <name>", "Create a bot account"]);
+                            table.add_row(row!["/webhooks [create|list|revoke]", "Manage incoming webhooks"]);
+                            table.add_row(row!["/users [filters]", "List accounts, e.g. role=admin seen=7 page=2"]);
+                            table.add_row(row!["/resetpw <username>", "Issue a temporary password"]);
+                            table.add_row(row!["/deluser <username>", "Delete an account"]);
+                            table.add_row(row!["/lock <username>", "Lock an account"]);
+                            table.add_row(row!["/unlock <username>", "Unlock an account"]);
                             table.add_row(row!["/ban <username>", "Ban a user"]);
                             table.add_row(row!["/unban <username>", "Unban a user"]);
                             table.add_row(row!["/mute <username>", "Mute a user"]);
                             table.add_row(row!["/unmute <username>", "Unmute a user"]);
+                            table.add_row(row!["/ping", "Check the connection, keeps idle sessions open"]);
                             table.add_row(row!["/help", "Show this help message"]);
-                            table.print(&mut response).unwrap();
-                            let response = String::from_utf8(response).unwrap();
-                            peer.lines.send(response).await?;
+                            peer.lines.send(commands::render_table(&mut table, width)).await?;
                         }
                         _ => {
+                            is_command = false;
                             if database::get_user_role(&conn, username).await? == "muted" {
                                 peer.lines.send("You are muted.".red().to_string()).await?;
                             } else {
-                                let msg = Message::from_input(username.to_string(), msg.to_string());
-                                database::store_message(&conn, &msg).await.unwrap_or_else(|e| {
-                                    tracing::error!("Failed to store message: {:?}", e);
-                                });
-                                let formatted_msg = msg.format(peer.color);
-                                state.broadcast(addr, &formatted_msg).await;
+                                let mut msg = Message::from_input(username.to_string(), msg.to_string());
+                                match database::store_message(&conn, &mut msg).await {
+                                    Ok(id) => database::store_mentions(&conn, id, &msg.mentions()).await?,
+                                    Err(e) => tracing::error!("Failed to store message: {:?}", e),
+                                }
+                                state.broadcast_message(addr, &msg, peer.settings.color()).await;
+                                if let Some(id) = msg.id {
+                                    if peer.settings.show_ids {
+                                        peer.lines.send(format!("#{}", id).bright_black().to_string()).await?;
+                                    }
+                                }
                             }
                         }
                     }
+                    if is_command {
+                        metrics::command(command);
+                    }
                 }
                 Some(Err(e)) => {
                     tracing::error!(
@@ -467,16 +1325,8 @@ async fn process(
 
     {
         let mut state = state.lock().await;
-        state.peers.remove(&addr);
-        state.usernames.remove(&addr);
-
-        tracing::info!("{} has left the chat", username);
-        state
-            .broadcast(
-                addr,
-                format!("\n\r<- User left: {0}\n\r", username.red().bold()).as_str(),
-            )
-            .await;
+        state.remove_session(addr);
+        state.announce_leave(&conn, username).await;
     }
 
     Ok(())
@@ -1,35 +1,123 @@
-use chrono::Utc;
+use chrono::{Duration, FixedOffset, NaiveTime, Utc};
 use colored::{Color, Colorize};
+use serde::Serialize;
 
+#[derive(Debug, Clone, Serialize)]
 pub struct Message {
+    pub id: Option<i64>,
     pub sender: String,
     pub content: String,
     pub timestamp: String,
+    pub edited: bool,
+    pub parent_id: Option<i64>,
 }
 
 impl Message {
-    pub fn from_database(sender: String, content: String, timestamp: String) -> Self {
+    pub fn from_database(
+        id: i64,
+        sender: String,
+        content: String,
+        timestamp: String,
+        edited: bool,
+        parent_id: Option<i64>,
+    ) -> Self {
         Message {
+            id: Some(id),
             sender,
             content,
             timestamp,
+            edited,
+            parent_id,
         }
     }
 
     pub fn from_input(sender: String, content: String) -> Self {
         Message {
+            id: None,
             sender,
             content,
             timestamp: Utc::now().format("%H:%M:%S").to_string(),
+            edited: false,
+            parent_id: None,
         }
     }
 
+    pub fn reply_to(mut self, parent_id: i64) -> Self {
+        self.parent_id = Some(parent_id);
+        self
+    }
+
+    /// Shifts the stored UTC timestamp into the given offset for display.
+    pub fn localize(mut self, offset: &FixedOffset) -> Self {
+        if let Ok(time) = NaiveTime::parse_from_str(&self.timestamp, "%H:%M:%S") {
+            let local = time + Duration::seconds(offset.local_minus_utc() as i64);
+            self.timestamp = local.format("%H:%M:%S").to_string();
+        }
+        self
+    }
+
     pub fn format(&self, color: Color) -> String {
+        let id = match self.id {
+            Some(id) => format!("{} ", format!("#{}", id).bright_black()),
+            None => String::new(),
+        };
+        let reply = match self.parent_id {
+            Some(parent_id) => format!("{} ", format!("↪ #{}", parent_id).bright_black()),
+            None => String::new(),
+        };
+        let edited = if self.edited { " (edited)" } else { "" };
         format!(
-            "{} {}: {}",
+            "{}{} {}: {}{}{}",
+            id,
             self.timestamp.bright_black(),
             self.sender.color(color),
-            self.content
+            reply,
+            self.content,
+            edited.bright_black()
         )
     }
+
+    /// Usernames addressed as `@name` in the content, without duplicates.
+    pub fn mentions(&self) -> Vec<String> {
+        let mut mentions: Vec<String> = Vec::new();
+        for word in self.content.split_whitespace() {
+            if let Some(name) = word.strip_prefix('@') {
+                let name = name.trim_end_matches(|c: char| !is_name_char(c));
+                if !name.is_empty() && !mentions.iter().any(|m| m == name) {
+                    mentions.push(name.to_string());
+                }
+            }
+        }
+        mentions
+    }
+
+    /// Makes `@username` stand out for the user being mentioned.
+    pub fn highlight_mentions(mut self, username: &str) -> Self {
+        let tag = format!("@{}", username);
+        self.content = self
+            .content
+            .split(' ')
+            .map(|word| match word.strip_prefix(tag.as_str()) {
+                Some(rest) if !rest.starts_with(is_name_char) => {
+                    format!("{}{}", tag.yellow().bold().underline(), rest)
+                }
+                _ => word.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        self
+    }
+
+    /// A one-line excerpt of the message, used to quote it above replies.
+    pub fn preview(&self, max_chars: usize) -> String {
+        let mut preview: String = self.content.chars().take(max_chars).collect();
+        if self.content.chars().count() > max_chars {
+            preview.push('…');
+        }
+        preview
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
 }
@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::Shared;
+
+/// How a peer is connected to the chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Telnet,
+    WebSocket,
+    Irc,
+}
+
+impl Transport {
+    const ALL: [Transport; 3] = [Transport::Telnet, Transport::WebSocket, Transport::Irc];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Telnet => "telnet",
+            Transport::WebSocket => "websocket",
+            Transport::Irc => "irc",
+        }
+    }
+}
+
+/// Upper bounds of the SQLite query latency buckets, in seconds. SQLite
+/// reports statement times with millisecond resolution.
+const QUERY_BUCKETS: [f64; 9] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+/// Statement kinds queries are grouped by, the last one catching the rest.
+const QUERY_KINDS: [&str; 5] = ["select", "insert", "update", "delete", "other"];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; QUERY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(QUERY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters collected while the server runs.
+#[derive(Default)]
+struct Metrics {
+    logins_succeeded: AtomicU64,
+    logins_failed: AtomicU64,
+    messages_broadcast: AtomicU64,
+    /// Connections accepted on the TCP port, including the proxy's.
+    tcp_connections: AtomicU64,
+    websocket_connections: AtomicU64,
+    irc_connections: AtomicU64,
+    commands: Mutex<BTreeMap<String, u64>>,
+    queries: [Histogram; QUERY_KINDS.len()],
+    /// Local addresses of the proxy's connections to the TCP port, so their
+    /// sessions can be told apart from telnet ones, mapped to the address of
+    /// the WebSocket client.
+    websocket_addrs: Mutex<HashMap<SocketAddr, SocketAddr>>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+fn get() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+pub fn login(succeeded: bool) {
+    let counter = if succeeded {
+        &get().logins_succeeded
+    } else {
+        &get().logins_failed
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn message_broadcast() {
+    get().messages_broadcast.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn command(name: &str) {
+    let mut commands = get().commands.lock().unwrap();
+    *commands.entry(name.to_string()).or_default() += 1;
+}
+
+pub fn connection(transport: Transport) {
+    let counter = match transport {
+        Transport::Telnet => &get().tcp_connections,
+        Transport::WebSocket => &get().websocket_connections,
+        Transport::Irc => &get().irc_connections,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Remembers a proxy connection to the TCP port while it is open.
+pub fn websocket_opened(local: SocketAddr, client: SocketAddr) {
+    get().websocket_addrs.lock().unwrap().insert(local, client);
+}
+
+pub fn websocket_closed(local: SocketAddr) {
+    get().websocket_addrs.lock().unwrap().remove(&local);
+}
+
+/// The WebSocket client behind a proxy connection to the TCP port.
+pub fn websocket_client(addr: SocketAddr) -> Option<SocketAddr> {
+    get().websocket_addrs.lock().unwrap().get(&addr).copied()
+}
+
+/// Whether a connection to the TCP port comes from the WebSocket proxy.
+pub fn transport_of(addr: SocketAddr) -> Transport {
+    if get().websocket_addrs.lock().unwrap().contains_key(&addr) {
+        Transport::WebSocket
+    } else {
+        Transport::Telnet
+    }
+}
+
+/// Profile hook for the SQLite connection, called after every statement.
+pub fn record_query(sql: &str, duration: Duration) {
+    let verb = sql
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let kind = QUERY_KINDS
+        .iter()
+        .position(|kind| *kind == verb)
+        .unwrap_or(QUERY_KINDS.len() - 1);
+    get().queries[kind].observe(duration);
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render(state: &Shared) -> String {
+    let metrics = get();
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP ferrum_connected_peers Logged in sessions.");
+    let _ = writeln!(out, "# TYPE ferrum_connected_peers gauge");
+    for transport in Transport::ALL {
+        let peers = state
+            .sessions
+            .values()
+            .filter(|session| session.transport == transport)
+            .count();
+        let _ = writeln!(
+            out,
+            "ferrum_connected_peers{{transport=\"{}\"}} {}",
+            transport.as_str(),
+            peers
+        );
+    }
+
+    let websocket = metrics.websocket_connections.load(Ordering::Relaxed);
+    let connections = [
+        (
+            Transport::Telnet,
+            metrics
+                .tcp_connections
+                .load(Ordering::Relaxed)
+                .saturating_sub(websocket),
+        ),
+        (Transport::WebSocket, websocket),
+        (
+            Transport::Irc,
+            metrics.irc_connections.load(Ordering::Relaxed),
+        ),
+    ];
+    let _ = writeln!(
+        out,
+        "# HELP ferrum_connections_total Accepted connections, logged in or not."
+    );
+    let _ = writeln!(out, "# TYPE ferrum_connections_total counter");
+    for (transport, count) in connections {
+        let _ = writeln!(
+            out,
+            "ferrum_connections_total{{transport=\"{}\"}} {}",
+            transport.as_str(),
+            count
+        );
+    }
+
+    let _ = writeln!(out, "# HELP ferrum_logins_total Login attempts.");
+    let _ = writeln!(out, "# TYPE ferrum_logins_total counter");
+    let _ = writeln!(
+        out,
+        "ferrum_logins_total{{result=\"succeeded\"}} {}",
+        metrics.logins_succeeded.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "ferrum_logins_total{{result=\"failed\"}} {}",
+        metrics.logins_failed.load(Ordering::Relaxed)
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP ferrum_messages_broadcast_total Chat messages sent to everyone."
+    );
+    let _ = writeln!(out, "# TYPE ferrum_messages_broadcast_total counter");
+    let _ = writeln!(
+        out,
+        "ferrum_messages_broadcast_total {}",
+        metrics.messages_broadcast.load(Ordering::Relaxed)
+    );
+
+    let _ = writeln!(out, "# HELP ferrum_commands_total Commands run, by name.");
+    let _ = writeln!(out, "# TYPE ferrum_commands_total counter");
+    for (name, count) in metrics.commands.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "ferrum_commands_total{{command=\"{}\"}} {}",
+            name, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP ferrum_peer_queue_depth Messages waiting to be sent to a session."
+    );
+    let _ = writeln!(out, "# TYPE ferrum_peer_queue_depth gauge");
+    for (addr, tx) in &state.peers {
+        let user = state.usernames.get(addr).map(String::as_str);
+        let _ = writeln!(
+            out,
+            "ferrum_peer_queue_depth{{peer=\"{}\",user=\"{}\"}} {}",
+            addr,
+            user.unwrap_or_default(),
+            tx.queued()
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP ferrum_sqlite_query_duration_seconds SQLite statement latency."
+    );
+    let _ = writeln!(out, "# TYPE ferrum_sqlite_query_duration_seconds histogram");
+    for (kind, histogram) in QUERY_KINDS.iter().zip(&metrics.queries) {
+        for (bound, bucket) in QUERY_BUCKETS.iter().zip(&histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "ferrum_sqlite_query_duration_seconds_bucket{{kind=\"{}\",le=\"{}\"}} {}",
+                kind,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(
+            out,
+            "ferrum_sqlite_query_duration_seconds_bucket{{kind=\"{}\",le=\"+Inf\"}} {}",
+            kind, count
+        );
+        let _ = writeln!(
+            out,
+            "ferrum_sqlite_query_duration_seconds_sum{{kind=\"{}\"}} {}",
+            kind, sum
+        );
+        let _ = writeln!(
+            out,
+            "ferrum_sqlite_query_duration_seconds_count{{kind=\"{}\"}} {}",
+            kind, count
+        );
+    }
+    out
+}
@@ -0,0 +1,72 @@
+use colored::{ColoredString, Colorize};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceState {
+    Online,
+    Away,
+    Busy,
+}
+
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub state: PresenceState,
+    /// Shown to whisperers while away.
+    pub away_message: Option<String>,
+    /// Free-form text set with `/status`, kept across state changes.
+    pub status: Option<String>,
+    /// Whether the away state was set by the idle timer rather than the user.
+    pub auto_away: bool,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Presence {
+            state: PresenceState::Online,
+            away_message: None,
+            status: None,
+            auto_away: false,
+        }
+    }
+}
+
+impl Presence {
+    pub fn label(&self) -> ColoredString {
+        match self.state {
+            PresenceState::Online => "Online".green(),
+            PresenceState::Away => "Away".yellow(),
+            PresenceState::Busy => "Busy".magenta(),
+        }
+    }
+
+    pub fn set_away(&mut self, message: Option<String>, auto: bool) {
+        self.state = PresenceState::Away;
+        self.away_message = message;
+        self.auto_away = auto;
+    }
+
+    pub fn set_busy(&mut self) {
+        self.state = PresenceState::Busy;
+        self.away_message = None;
+        self.auto_away = false;
+    }
+
+    pub fn set_back(&mut self) {
+        self.state = PresenceState::Online;
+        self.away_message = None;
+        self.auto_away = false;
+    }
+
+    /// The auto-reply sent to whisperers, if the user is not available.
+    pub fn auto_reply(&self, username: &str) -> Option<String> {
+        match self.state {
+            PresenceState::Online => None,
+            PresenceState::Away => Some(match &self.away_message {
+                Some(message) => format!("{} is away: {}", username, message),
+                None => format!("{} is away.", username),
+            }),
+            PresenceState::Busy => Some(format!("{} is busy.", username)),
+        }
+    }
+}
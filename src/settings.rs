@@ -0,0 +1,101 @@
+use std::str::FromStr;
+
+use chrono::FixedOffset;
+use colored::Color;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSettings {
+    pub color: String,
+    pub timezone: String,
+    pub notifications: bool,
+    pub show_ids: bool,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings {
+            color: "green".to_string(),
+            timezone: "UTC".to_string(),
+            notifications: true,
+            show_ids: true,
+        }
+    }
+}
+
+impl UserSettings {
+    pub fn color(&self) -> Color {
+        Color::from_str(&self.color).unwrap_or(Color::Green)
+    }
+
+    pub fn offset(&self) -> FixedOffset {
+        parse_timezone(&self.timezone).unwrap_or_else(utc)
+    }
+}
+
+pub fn utc() -> FixedOffset {
+    FixedOffset::east_opt(0).unwrap()
+}
+
+/// Parses `UTC`, `UTC+2`, `+02:00`, `-0530` or `-5` into a fixed offset.
+pub fn parse_timezone(tz: &str) -> Option<FixedOffset> {
+    let tz = tz.trim();
+    let offset = tz
+        .strip_prefix("UTC")
+        .or_else(|| tz.strip_prefix("utc"))
+        .unwrap_or(tz);
+    if offset.is_empty() {
+        return Some(utc());
+    }
+
+    let (sign, rest) = match offset.as_bytes()[0] {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if rest.len() == 4 && rest.is_ascii() => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let digits = |part: &str| -> Option<i32> {
+        if part.is_empty() || part.len() > 2 || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let hours = digits(hours)?;
+    let minutes = digits(minutes)?;
+    if hours > 23 || minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(tz: &str) -> Option<i32> {
+        parse_timezone(tz).map(|offset| offset.local_minus_utc())
+    }
+
+    #[test]
+    fn parses_supported_forms() {
+        assert_eq!(seconds("UTC"), Some(0));
+        assert_eq!(seconds("utc+2"), Some(7200));
+        assert_eq!(seconds("+02:00"), Some(7200));
+        assert_eq!(seconds("-0530"), Some(-19800));
+        assert_eq!(seconds("-5"), Some(-18000));
+        assert_eq!(seconds("+23:59"), Some(86340));
+    }
+
+    #[test]
+    fn rejects_out_of_range_and_malformed_offsets() {
+        for tz in [
+            "+999999", "+24", "+1:60", "--5", "-+5", "+", "+1:", "CET", "+٣",
+        ] {
+            assert_eq!(seconds(tz), None, "{}", tz);
+        }
+    }
+}
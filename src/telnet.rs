@@ -0,0 +1,404 @@
+use std::collections::HashSet;
+
+use bytes::{BufMut, BytesMut};
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodec, LinesCodecError};
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+pub const ECHO: u8 = 1;
+pub const SUPPRESS_GO_AHEAD: u8 = 3;
+pub const NAWS: u8 = 31;
+
+/// An option command sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Negotiation {
+    Will(u8),
+    Wont(u8),
+    Do(u8),
+    Dont(u8),
+}
+
+impl Negotiation {
+    fn bytes(self) -> [u8; 3] {
+        match self {
+            Negotiation::Will(option) => [IAC, WILL, option],
+            Negotiation::Wont(option) => [IAC, WONT, option],
+            Negotiation::Do(option) => [IAC, DO, option],
+            Negotiation::Dont(option) => [IAC, DONT, option],
+        }
+    }
+}
+
+/// An answer to an option command the client sent, see [`answer`].
+#[derive(Debug, Clone, Copy)]
+struct Reply(Negotiation);
+
+/// Line codec that understands telnet option negotiation.
+///
+/// Commands are removed from the input so they never end up in usernames or
+/// messages. The server side of ECHO and SUPPRESS-GO-AHEAD and the client side
+/// of NAWS are supported, everything else is refused. Clients that never send
+/// a command, like `nc`, are treated as plain line-based connections.
+#[derive(Debug, Default)]
+pub struct TelnetCodec {
+    lines: LinesCodec,
+    clean: BytesMut,
+    /// Whether the client has sent any telnet command.
+    telnet: bool,
+    /// Options we perform, e.g. ECHO while a password is typed.
+    local: HashSet<u8>,
+    /// Options the client performs, e.g. NAWS.
+    remote: HashSet<u8>,
+    /// Requests we sent that the client has not answered yet.
+    requested: HashSet<Negotiation>,
+    replies: Vec<Negotiation>,
+    window_size: Option<(u16, u16)>,
+}
+
+impl TelnetCodec {
+    pub fn new() -> Self {
+        TelnetCodec::default()
+    }
+
+    /// Whether the client speaks telnet rather than plain lines.
+    pub fn is_telnet(&self) -> bool {
+        self.telnet
+    }
+
+    /// Terminal width and height reported through NAWS.
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    /// Moves everything but telnet commands from `src` into the line buffer.
+    /// An incomplete command at the end stays in `src` until more data arrives.
+    fn filter(&mut self, src: &mut BytesMut) {
+        let mut i = 0;
+        while i < src.len() {
+            if src[i] != IAC {
+                self.clean.put_u8(src[i]);
+                i += 1;
+                continue;
+            }
+            let Some(len) = command_len(&src[i..]) else {
+                break;
+            };
+            let command = src[i..i + len].to_vec();
+            self.handle_command(&command);
+            i += len;
+        }
+        let _ = src.split_to(i);
+    }
+
+    fn handle_command(&mut self, command: &[u8]) {
+        self.telnet = true;
+        match command[1] {
+            IAC => self.clean.put_u8(IAC),
+            WILL => self.remote_option(command[2], true),
+            WONT => self.remote_option(command[2], false),
+            DO => self.local_option(command[2], true),
+            DONT => self.local_option(command[2], false),
+            SB if command.get(2) == Some(&NAWS) => {
+                let data = unescape(&command[3..command.len() - 2]);
+                if data.len() == 4 {
+                    let width = u16::from_be_bytes([data[0], data[1]]);
+                    let height = u16::from_be_bytes([data[2], data[3]]);
+                    self.window_size = (width > 0).then_some((width, height));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles WILL/WONT from the client, answering only when the state
+    /// actually changes so the two sides cannot loop.
+    fn remote_option(&mut self, option: u8, enable: bool) {
+        let request = if enable {
+            Negotiation::Do(option)
+        } else {
+            Negotiation::Dont(option)
+        };
+        let answered = self.requested.remove(&request);
+        if enable && option != NAWS {
+            if !answered {
+                self.replies.push(Negotiation::Dont(option));
+            }
+            return;
+        }
+        if enable == self.remote.contains(&option) {
+            return;
+        }
+        if enable {
+            self.remote.insert(option);
+        } else {
+            self.remote.remove(&option);
+            self.window_size = None;
+        }
+        if !answered {
+            self.replies.push(request);
+        }
+    }
+
+    /// Handles DO/DONT from the client. ECHO is only performed when we
+    /// offered it, since the server does not echo input on its own.
+    fn local_option(&mut self, option: u8, enable: bool) {
+        let offer = if enable {
+            Negotiation::Will(option)
+        } else {
+            Negotiation::Wont(option)
+        };
+        let answered = self.requested.remove(&offer);
+        let supported = option == SUPPRESS_GO_AHEAD || (option == ECHO && answered);
+        if enable && !supported {
+            if !answered {
+                self.replies.push(Negotiation::Wont(option));
+            }
+            return;
+        }
+        if enable == self.local.contains(&option) {
+            return;
+        }
+        if enable {
+            self.local.insert(option);
+        } else {
+            self.local.remove(&option);
+        }
+        if !answered {
+            self.replies.push(offer);
+        }
+    }
+
+    /// Removes telnet commands from raw output for clients that are not telnet,
+    /// keeping an incomplete command in `src` for the next call.
+    pub fn strip(&mut self, src: &mut BytesMut) -> BytesMut {
+        self.filter(src);
+        self.clean.split()
+    }
+}
+
+/// Length of the telnet command at the start of `buf`, if it is complete.
+fn command_len(buf: &[u8]) -> Option<usize> {
+    match buf.get(1)? {
+        &WILL | &WONT | &DO | &DONT => (buf.len() >= 3).then_some(3),
+        &SB => {
+            let mut i = 2;
+            while i + 1 < buf.len() {
+                match (buf[i], buf[i + 1]) {
+                    (IAC, SE) => return Some(i + 2),
+                    (IAC, _) => i += 2,
+                    _ => i += 1,
+                }
+            }
+            None
+        }
+        _ => Some(2),
+    }
+}
+
+/// Undoes the doubling of 255 bytes inside subnegotiation data.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        out.push(data[i]);
+        i += if data[i] == IAC && data.get(i + 1) == Some(&IAC) {
+            2
+        } else {
+            1
+        };
+    }
+    out
+}
+
+/// Applies backspaces and drops the NUL telnet sends after a bare CR, for
+/// clients that send keystrokes one by one instead of editing lines locally.
+fn edit_line(line: String) -> String {
+    let mut edited = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '\x08' | '\x7f' => {
+                edited.pop();
+            }
+            '\0' => {}
+            c => edited.push(c),
+        }
+    }
+    edited
+}
+
+impl Decoder for TelnetCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.filter(src);
+        Ok(self.lines.decode(&mut self.clean)?.map(edit_line))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.filter(src);
+        Ok(self.lines.decode_eof(&mut self.clean)?.map(edit_line))
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for TelnetCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: T, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.lines.encode(line, dst)
+    }
+}
+
+impl Encoder<Negotiation> for TelnetCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, item: Negotiation, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.requested.insert(item);
+        dst.extend_from_slice(&item.bytes());
+        Ok(())
+    }
+}
+
+impl Encoder<Reply> for TelnetCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, item: Reply, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        dst.extend_from_slice(&item.0.bytes());
+        Ok(())
+    }
+}
+
+/// Offers the options we support. Telnet clients answer, which is how they
+/// are told apart from plain line-based clients.
+pub async fn negotiate(lines: &mut Framed<TcpStream, TelnetCodec>) -> Result<(), LinesCodecError> {
+    lines.feed(Negotiation::Will(SUPPRESS_GO_AHEAD)).await?;
+    lines.send(Negotiation::Do(NAWS)).await
+}
+
+/// Sends the answers to option commands received since the last call.
+pub async fn answer(lines: &mut Framed<TcpStream, TelnetCodec>) -> Result<(), LinesCodecError> {
+    let replies = std::mem::take(&mut lines.codec_mut().replies);
+    if replies.is_empty() {
+        return Ok(());
+    }
+    for reply in replies {
+        lines.feed(Reply(reply)).await?;
+    }
+    SinkExt::<Reply>::flush(lines).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(codec: &mut TelnetCodec, src: &mut BytesMut, bytes: &[u8]) -> Option<String> {
+        src.extend_from_slice(bytes);
+        codec.decode(src).unwrap()
+    }
+
+    fn replies(codec: &mut TelnetCodec) -> Vec<Negotiation> {
+        std::mem::take(&mut codec.replies)
+    }
+
+    #[test]
+    fn command_split_across_reads_waits_for_the_rest() {
+        let mut codec = TelnetCodec::new();
+        let mut src = BytesMut::new();
+        assert_eq!(decode(&mut codec, &mut src, b"hel\xff"), None);
+        assert_eq!(&src[..], &[IAC]);
+        assert_eq!(decode(&mut codec, &mut src, &[WILL]), None);
+        assert_eq!(
+            decode(&mut codec, &mut src, &[NAWS, b'l', b'o', b'\r', b'\n']).as_deref(),
+            Some("hello")
+        );
+        assert!(src.is_empty());
+        assert!(codec.is_telnet());
+        assert_eq!(replies(&mut codec), [Negotiation::Do(NAWS)]);
+    }
+
+    #[test]
+    fn escaped_iac_is_kept_as_data() {
+        let mut codec = TelnetCodec::new();
+        let mut src = BytesMut::from(&[b'a', IAC, IAC, b'b', IAC][..]);
+        assert_eq!(&codec.strip(&mut src)[..], &[b'a', IAC, b'b']);
+        assert_eq!(&src[..], &[IAC]);
+        src.extend_from_slice(&[IAC, b'c']);
+        assert_eq!(&codec.strip(&mut src)[..], &[IAC, b'c']);
+    }
+
+    #[test]
+    fn naws_subnegotiation_unescapes_255() {
+        let mut codec = TelnetCodec::new();
+        let mut src = BytesMut::new();
+        // Width 255 is sent as 0 IAC IAC, and the SE arrives in a later read.
+        decode(
+            &mut codec,
+            &mut src,
+            &[IAC, SB, NAWS, 0, IAC, IAC, 0, 24, IAC],
+        );
+        assert_eq!(codec.window_size(), None);
+        assert_eq!(
+            decode(&mut codec, &mut src, &[SE, b'x', b'\n']).as_deref(),
+            Some("x")
+        );
+        assert_eq!(codec.window_size(), Some((255, 24)));
+    }
+
+    #[test]
+    fn answers_do_not_loop() {
+        let mut codec = TelnetCodec::new();
+        let mut src = BytesMut::new();
+        let mut out = BytesMut::new();
+        codec.encode(Negotiation::Do(NAWS), &mut out).unwrap();
+        codec
+            .encode(Negotiation::Will(SUPPRESS_GO_AHEAD), &mut out)
+            .unwrap();
+
+        // Answers to our own requests are not answered again.
+        decode(
+            &mut codec,
+            &mut src,
+            &[IAC, WILL, NAWS, IAC, DO, SUPPRESS_GO_AHEAD],
+        );
+        assert_eq!(replies(&mut codec), []);
+        // Repeating an option that is already in effect changes nothing.
+        decode(
+            &mut codec,
+            &mut src,
+            &[IAC, WILL, NAWS, IAC, DO, SUPPRESS_GO_AHEAD],
+        );
+        assert_eq!(replies(&mut codec), []);
+
+        // Unsupported options are refused once, and the client's agreement
+        // to the refusal is not answered.
+        decode(&mut codec, &mut src, &[IAC, WILL, 24, IAC, DO, ECHO]);
+        assert_eq!(
+            replies(&mut codec),
+            [Negotiation::Dont(24), Negotiation::Wont(ECHO)]
+        );
+        decode(&mut codec, &mut src, &[IAC, WONT, 24, IAC, DONT, ECHO]);
+        assert_eq!(replies(&mut codec), []);
+
+        // Turning an option off is acknowledged exactly once.
+        decode(&mut codec, &mut src, &[IAC, WONT, NAWS, IAC, WONT, NAWS]);
+        assert_eq!(replies(&mut codec), [Negotiation::Dont(NAWS)]);
+    }
+
+    #[test]
+    fn backspaces_and_nuls_are_applied() {
+        let mut codec = TelnetCodec::new();
+        let mut src = BytesMut::new();
+        assert_eq!(
+            decode(&mut codec, &mut src, b"ab\x08c\x7fd\0\r\n").as_deref(),
+            Some("ad")
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Prefix of generated tokens, so they are easy to recognize in logs and configs.
+const TOKEN_PREFIX: &str = "fst_";
+
+/// What an API token may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Log in with `token <value>` and post messages.
+    Chat,
+    /// Read users and message history through the HTTP API.
+    Read,
+    /// Ban and mute through the HTTP API; the account must also be an admin.
+    Moderate,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Chat, Scope::Read, Scope::Moderate];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Chat => "chat",
+            Scope::Read => "read",
+            Scope::Moderate => "moderate",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("Unknown scope '{}', use chat, read or moderate.", s))
+    }
+}
+
+/// Parses a comma separated scope list as stored in the database.
+pub fn parse_scopes(scopes: &str) -> Result<Vec<Scope>, String> {
+    scopes
+        .split(',')
+        .filter(|scope| !scope.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Generates a new token value. Only its hash is stored.
+pub fn generate_token() -> String {
+    format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 40)
+    )
+}
+
+/// Hashes a token for storage and lookup. Tokens are long and random, so a
+/// plain SHA-256 is enough and keeps per-request checks cheap.
+pub fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Lowercase hex encoding, as used for hashes and signatures.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,194 @@
+use std::error::Error;
+use std::fmt;
+
+use rand::distributions::{Alphanumeric, DistString};
+
+use crate::config;
+
+/// Why a username or password was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    UsernameLength { min: usize, max: usize },
+    UsernameStart,
+    UsernameCharacter(char),
+    UsernameReserved(String),
+    UsernameTaken(String),
+    PasswordTooShort(usize),
+    PasswordTooLong,
+    PasswordTooSimple(usize),
+    PasswordContainsUsername,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::UsernameLength { min, max } => {
+                write!(f, "Usernames must be {} to {} characters long.", min, max)
+            }
+            ValidationError::UsernameStart => write!(f, "Usernames must start with a letter."),
+            ValidationError::UsernameCharacter(c) => {
+                write!(f, "Usernames may not contain '{}'.", c.escape_default())
+            }
+            ValidationError::UsernameReserved(name) => {
+                write!(f, "The username '{}' is reserved.", name)
+            }
+            ValidationError::UsernameTaken(name) => {
+                write!(f, "The username '{}' is already taken.", name)
+            }
+            ValidationError::PasswordTooShort(min) => {
+                write!(f, "Passwords must be at least {} characters long.", min)
+            }
+            ValidationError::PasswordTooLong => {
+                write!(
+                    f,
+                    "Passwords may be at most {} bytes long.",
+                    MAX_PASSWORD_BYTES
+                )
+            }
+            ValidationError::PasswordTooSimple(classes) => write!(
+                f,
+                "Passwords must mix at least {} of lowercase, uppercase, digits and symbols.",
+                classes
+            ),
+            ValidationError::PasswordContainsUsername => {
+                write!(f, "Passwords may not contain the username.")
+            }
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// bcrypt ignores everything after this many bytes.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+/// Checks a new username against the configured length, characters and
+/// reserved names. Uniqueness is checked by the database.
+pub fn validate_username(username: &str) -> Result<(), ValidationError> {
+    let config = config::get();
+    let length = username.chars().count();
+    if length < config.username_min_length || length > config.username_max_length {
+        return Err(ValidationError::UsernameLength {
+            min: config.username_min_length,
+            max: config.username_max_length,
+        });
+    }
+    if !username.starts_with(|c: char| c.is_alphabetic()) {
+        return Err(ValidationError::UsernameStart);
+    }
+    if let Some(c) = username
+        .chars()
+        .find(|&c| !c.is_alphanumeric() && !config.username_extra_chars.contains(c))
+    {
+        return Err(ValidationError::UsernameCharacter(c));
+    }
+    if config
+        .reserved_usernames
+        .iter()
+        .chain([&config.api_bot_name])
+        .any(|reserved| reserved.eq_ignore_ascii_case(username))
+    {
+        return Err(ValidationError::UsernameReserved(username.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks a new password against the configured minimum length and the
+/// number of character classes it has to mix.
+pub fn validate_password(username: &str, password: &str) -> Result<(), ValidationError> {
+    let config = config::get();
+    if password.chars().count() < config.password_min_length {
+        return Err(ValidationError::PasswordTooShort(
+            config.password_min_length,
+        ));
+    }
+    if password.len() > MAX_PASSWORD_BYTES {
+        return Err(ValidationError::PasswordTooLong);
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ]
+    .into_iter()
+    .filter(|&class| class)
+    .count();
+    if classes < config.password_min_classes {
+        return Err(ValidationError::PasswordTooSimple(
+            config.password_min_classes,
+        ));
+    }
+    if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+        return Err(ValidationError::PasswordContainsUsername);
+    }
+    Ok(())
+}
+
+/// Generates the one-time password handed out by `/resetpw`.
+pub fn temporary_password() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usernames_follow_the_default_rules() {
+        assert_eq!(validate_username("alice_1"), Ok(()));
+        assert_eq!(validate_username("Zoë-x"), Ok(()));
+        assert!(matches!(
+            validate_username("al"),
+            Err(ValidationError::UsernameLength { .. })
+        ));
+        assert!(matches!(
+            validate_username(&"a".repeat(21)),
+            Err(ValidationError::UsernameLength { .. })
+        ));
+        assert_eq!(
+            validate_username("1alice"),
+            Err(ValidationError::UsernameStart)
+        );
+        assert_eq!(
+            validate_username("al ice"),
+            Err(ValidationError::UsernameCharacter(' '))
+        );
+        assert_eq!(
+            validate_username("al\x1bice"),
+            Err(ValidationError::UsernameCharacter('\x1b'))
+        );
+        assert_eq!(
+            validate_username("Admin"),
+            Err(ValidationError::UsernameReserved("Admin".to_string()))
+        );
+    }
+
+    #[test]
+    fn passwords_follow_the_default_rules() {
+        assert_eq!(validate_password("alice", "Secret123!"), Ok(()));
+        assert_eq!(
+            validate_password("alice", "Sh0rt"),
+            Err(ValidationError::PasswordTooShort(8))
+        );
+        assert_eq!(
+            validate_password("alice", "lowercaseonly"),
+            Err(ValidationError::PasswordTooSimple(2))
+        );
+        assert_eq!(
+            validate_password("alice", "xALICEx99"),
+            Err(ValidationError::PasswordContainsUsername)
+        );
+        assert_eq!(
+            validate_password("alice", &"aB3".repeat(25)),
+            Err(ValidationError::PasswordTooLong)
+        );
+    }
+
+    #[test]
+    fn temporary_passwords_are_random() {
+        let password = temporary_password();
+        assert_eq!(password.len(), 12);
+        assert_ne!(password, temporary_password());
+    }
+}
@@ -0,0 +1,362 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Uri};
+use rusqlite::Connection;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, Notify};
+
+use crate::database::{self, WebhookDelivery};
+use crate::{config, tokens, Shared};
+
+/// How long a webhook endpoint gets to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait between two attempts of the same delivery.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Queues every published chat event for the configured webhook URLs and
+/// delivers the queue in the background. The queue lives in the database,
+/// so deliveries pending at shutdown are sent after the next start.
+pub async fn run(state: Arc<Mutex<Shared>>, conn: Arc<Mutex<Connection>>) {
+    let config = config::get();
+    let urls: Vec<&String> = config
+        .webhook_urls
+        .iter()
+        .filter(|url| match url.parse::<Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => true,
+            Ok(uri) if uri.scheme_str() == Some("https") => {
+                tracing::warn!(
+                    "skipping webhook {}: https is not supported, deliver through a proxy that adds TLS",
+                    url
+                );
+                false
+            }
+            _ => {
+                tracing::warn!("skipping webhook {}: not an http:// URL", url);
+                false
+            }
+        })
+        .collect();
+    if urls.is_empty() {
+        return;
+    }
+    let mut events = state.lock().await.events.subscribe();
+    let wake = Arc::new(Notify::new());
+    tokio::spawn(deliver(conn.clone(), wake.clone()));
+    tracing::info!(
+        "sending webhooks to {}",
+        urls.iter()
+            .map(|url| url.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("webhooks fell behind, {} events were not queued", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if !config.webhook_events.is_empty()
+            && !config
+                .webhook_events
+                .iter()
+                .any(|name| name == event.name())
+        {
+            continue;
+        }
+        let payload = event.to_json();
+        for url in &urls {
+            if let Err(e) = database::enqueue_webhook(&conn, url, event.name(), &payload).await {
+                tracing::error!("failed to queue webhook for {}; error = {:?}", url, e);
+            }
+        }
+        wake.notify_one();
+    }
+}
+
+async fn deliver(conn: Arc<Mutex<Connection>>, wake: Arc<Notify>) {
+    let client = Client::new();
+    let config = config::get();
+    loop {
+        deliver_due(
+            &conn,
+            &client,
+            config.webhook_secret.as_deref(),
+            config.webhook_max_attempts,
+        )
+        .await;
+        tokio::select! {
+            _ = wake.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+    }
+}
+
+/// Sends the deliveries that are due once, scheduling a retry for each one
+/// that fails until `max_attempts` is reached.
+async fn deliver_due(
+    conn: &Mutex<Connection>,
+    client: &Client<HttpConnector>,
+    secret: Option<&str>,
+    max_attempts: u32,
+) {
+    let due = database::get_due_webhooks(conn, 50)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("failed to read the webhook queue; error = {:?}", e);
+            Vec::new()
+        });
+    for delivery in due {
+        let result = match send(client, &delivery, secret).await {
+            Ok(()) => database::mark_webhook_delivered(conn, delivery.id).await,
+            Err(error) => {
+                let attempts = delivery.attempts + 1;
+                let retry_at = (attempts < max_attempts)
+                    .then(|| (Utc::now() + retry_delay(attempts)).to_rfc3339());
+                tracing::warn!(
+                    "webhook {} to {} failed (attempt {}): {}",
+                    delivery.id,
+                    delivery.url,
+                    attempts,
+                    error
+                );
+                database::mark_webhook_failed(conn, delivery.id, &error, retry_at).await
+            }
+        };
+        if let Err(e) = result {
+            tracing::error!("failed to update webhook {}; error = {:?}", delivery.id, e);
+        }
+    }
+}
+
+/// Exponential backoff starting at the configured delay.
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    let delay = config::get()
+        .webhook_retry_delay
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY);
+    chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::hours(1))
+}
+
+async fn send(
+    client: &Client<HttpConnector>,
+    delivery: &WebhookDelivery,
+    secret: Option<&str>,
+) -> Result<(), String> {
+    let mut request = Request::post(&delivery.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Ferrum-Event", &delivery.event)
+        .header("X-Ferrum-Delivery", delivery.id);
+    if let Some(secret) = secret {
+        request = request.header("X-Ferrum-Signature", signature(secret, &delivery.payload));
+    }
+    let request = request
+        .body(Body::from(delivery.payload.clone()))
+        .map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("HTTP {}", response.status()))
+    }
+}
+
+/// `sha256=<hex>` HMAC of the body, so receivers can check the sender.
+fn signature(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", tokens::hex(&mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    use super::*;
+
+    /// What the stub endpoint was sent.
+    struct Received {
+        event: String,
+        delivery: String,
+        signature: Option<String>,
+        body: String,
+    }
+
+    /// A local endpoint answering every request with a settable status.
+    struct Stub {
+        addr: SocketAddr,
+        status: Arc<AtomicU16>,
+        received: Arc<std::sync::Mutex<Vec<Received>>>,
+    }
+
+    impl Stub {
+        async fn start() -> Stub {
+            let status = Arc::new(AtomicU16::new(200));
+            let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let (answer, log) = (status.clone(), received.clone());
+            let make_service = make_service_fn(move |_| {
+                let (answer, log) = (answer.clone(), log.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let (answer, log) = (answer.clone(), log.clone());
+                        async move {
+                            let header = |name: &str| {
+                                req.headers()
+                                    .get(name)
+                                    .and_then(|value| value.to_str().ok())
+                                    .map(str::to_string)
+                            };
+                            let event = header("X-Ferrum-Event").unwrap_or_default();
+                            let delivery = header("X-Ferrum-Delivery").unwrap_or_default();
+                            let signature = header("X-Ferrum-Signature");
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            log.lock().unwrap().push(Received {
+                                event,
+                                delivery,
+                                signature,
+                                body: String::from_utf8(body.to_vec()).unwrap(),
+                            });
+                            let response = Response::builder()
+                                .status(answer.load(Ordering::SeqCst))
+                                .body(Body::empty())
+                                .unwrap();
+                            Ok::<_, Infallible>(response)
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            Stub {
+                addr,
+                status,
+                received,
+            }
+        }
+
+        fn url(&self) -> String {
+            format!("http://{}/hook", self.addr)
+        }
+
+        fn requests(&self) -> usize {
+            self.received.lock().unwrap().len()
+        }
+    }
+
+    fn queue() -> Mutex<Connection> {
+        let conn = Connection::open_in_memory().unwrap();
+        Mutex::new(database::create_schema(conn).unwrap())
+    }
+
+    /// Attempts so far, and whether the delivery succeeded or was given up.
+    async fn progress(conn: &Mutex<Connection>, id: i64) -> (u32, bool, bool) {
+        conn.lock()
+            .await
+            .query_row(
+                "SELECT attempts, delivered_at IS NOT NULL, failed FROM webhook_deliveries WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn signature_is_hex_hmac_sha256() {
+        assert_eq!(
+            signature("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload_and_marks_it_delivered() {
+        let stub = Stub::start().await;
+        let conn = queue();
+        let payload = r#"{"event":"user_joined","username":"alice"}"#;
+        let id = database::enqueue_webhook(&conn, &stub.url(), "user_joined", payload)
+            .await
+            .unwrap();
+
+        deliver_due(&conn, &Client::new(), Some("s3cret"), 3).await;
+
+        {
+            let received = stub.received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].event, "user_joined");
+            assert_eq!(received[0].delivery, id.to_string());
+            assert_eq!(received[0].body, payload);
+            assert_eq!(
+                received[0].signature.as_deref(),
+                Some(signature("s3cret", payload).as_str())
+            );
+        }
+        assert_eq!(progress(&conn, id).await, (1, true, false));
+
+        deliver_due(&conn, &Client::new(), Some("s3cret"), 3).await;
+        assert_eq!(stub.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_a_failed_delivery_once_it_is_due() {
+        let stub = Stub::start().await;
+        stub.status.store(500, Ordering::SeqCst);
+        let conn = queue();
+        let id = database::enqueue_webhook(&conn, &stub.url(), "user_left", "{}")
+            .await
+            .unwrap();
+
+        deliver_due(&conn, &Client::new(), None, 3).await;
+        assert_eq!(progress(&conn, id).await, (1, false, false));
+        assert_eq!(stub.received.lock().unwrap()[0].signature, None);
+
+        // The retry is scheduled after a backoff, so nothing is sent yet.
+        deliver_due(&conn, &Client::new(), None, 3).await;
+        assert_eq!(stub.requests(), 1);
+
+        conn.lock()
+            .await
+            .execute(
+                "UPDATE webhook_deliveries SET next_attempt_at = ?1 WHERE id = ?2",
+                rusqlite::params![Utc::now().to_rfc3339(), id],
+            )
+            .unwrap();
+        stub.status.store(200, Ordering::SeqCst);
+        deliver_due(&conn, &Client::new(), None, 3).await;
+        assert_eq!(progress(&conn, id).await, (2, true, false));
+        let received = stub.received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].delivery, received[1].delivery);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let stub = Stub::start().await;
+        stub.status.store(503, Ordering::SeqCst);
+        let conn = queue();
+        let id = database::enqueue_webhook(&conn, &stub.url(), "user_left", "{}")
+            .await
+            .unwrap();
+
+        deliver_due(&conn, &Client::new(), None, 1).await;
+        assert_eq!(progress(&conn, id).await, (1, false, true));
+    }
+}
@@ -1,47 +1,244 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
 use futures::SinkExt;
 use futures::StreamExt;
 use futures::TryStreamExt;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
 use tokio_tungstenite::accept_async;
+use tracing::Instrument;
 use tungstenite::Message;
 
+use crate::config;
+use crate::health::{self, Listener};
+use crate::logging;
+use crate::metrics::{self, Transport};
+use crate::telnet::TelnetCodec;
+
+/// The bundled browser client, served to plain HTTP requests.
+const CLIENT_HTML: &str = include_str!("../static/index.html");
+/// Longest request head read before deciding how to answer a connection.
+const MAX_REQUEST_HEAD: usize = 8192;
+/// How long a new connection gets to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub async fn websocket_proxy() {
     let ws_listener = TcpListener::bind("127.0.0.1:8081").await.unwrap();
     tracing::info!("websocket proxy listening on 127.0.0.1:8081");
+    let _running = health::running(Listener::WebSocket);
 
     loop {
-        let (stream, _) = ws_listener.accept().await.unwrap();
-        let ws_stream = accept_async(stream).await.unwrap();
-        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-
-        let tcp_stream = TcpStream::connect("127.0.0.1:6142").await.unwrap();
-        let (mut tcp_reader, mut tcp_writer) = tcp_stream.into_split();
-
-        // Forward messages from WebSocket to TCP
-        tokio::spawn(async move {
-            while let Some(msg) = ws_receiver.try_next().await.unwrap() {
-                if let Message::Text(text) = msg {
-                    tcp_writer.write_all(text.as_bytes()).await.unwrap();
+        let accepted = tokio::select! {
+            accepted = ws_listener.accept() => accepted,
+            _ = health::shutdown() => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("failed to accept a websocket connection: {:?}", e);
+                continue;
+            }
+        };
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle(stream, addr).await {
+                    tracing::debug!("websocket connection from {} failed: {:?}", addr, e);
                 }
             }
-        });
-
-        // Forward messages from TCP to WebSocket
-        tokio::spawn(async move {
-            let mut buf = vec![0; 1024];
-            loop {
-                let n = tcp_reader.read(&mut buf).await.unwrap();
-                if n == 0 {
-                    break;
+            .instrument(logging::connection_span(addr)),
+        );
+    }
+}
+
+/// Proxies WebSocket upgrades to the chat and answers every other request
+/// with the bundled client.
+async fn handle(stream: TcpStream, addr: SocketAddr) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, peek_head(&stream)).await??;
+    let is_upgrade = head.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        })
+    });
+    if is_upgrade {
+        proxy(stream, addr).await
+    } else {
+        serve_client(stream, &head).await
+    }
+}
+
+/// Reads the request head without consuming it, so a WebSocket handshake
+/// can still see it.
+async fn peek_head(stream: &TcpStream) -> std::io::Result<String> {
+    let mut buf = vec![0; MAX_REQUEST_HEAD];
+    loop {
+        let n = stream.peek(&mut buf).await?;
+        let head = &buf[..n];
+        if n == 0 || n == buf.len() || head.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(String::from_utf8_lossy(head).into_owned());
+        }
+        // Peeking returns immediately while data is buffered, so wait for more.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+async fn serve_client(
+    mut stream: TcpStream,
+    head: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    let (status, content_type, body) = match (method, path) {
+        ("GET" | "HEAD", "/" | "/index.html") => {
+            ("200 OK", "text/html; charset=utf-8", CLIENT_HTML)
+        }
+        ("GET" | "HEAD", _) => ("404 Not Found", "text/plain", "Not found\n"),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n",
+        ),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn proxy(stream: TcpStream, addr: SocketAddr) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let ws_stream = accept_async(stream).await?;
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let tcp_stream = TcpStream::connect("127.0.0.1:6142").await?;
+    let local = tcp_stream.local_addr()?;
+    metrics::connection(Transport::WebSocket);
+    metrics::websocket_opened(local, addr);
+    tracing::debug!("proxying websocket client {} as {}", addr, local);
+    let (mut tcp_reader, mut tcp_writer) = tcp_stream.into_split();
+
+    let last_pong = Arc::new(Mutex::new(Instant::now()));
+    let last_pong_clone = last_pong.clone();
+
+    // Forward messages from WebSocket to TCP
+    tokio::spawn(async move {
+        while let Ok(Some(msg)) = ws_receiver.try_next().await {
+            match msg {
+                Message::Text(text) => {
+                    if let Err(e) = tcp_writer.write_all(text.as_bytes()).await {
+                        tracing::debug!("chat connection closed while forwarding; error = {:?}", e);
+                        break;
+                    }
                 }
+                Message::Pong(_) => *last_pong_clone.lock().await = Instant::now(),
+                _ => {}
+            }
+        }
+    });
+
+    // Forward messages from TCP to WebSocket, pinging the client while idle
+    tokio::spawn(async move {
+        let ping_interval = config::get().websocket_ping_interval;
+        let mut ping = tokio::time::interval(ping_interval.unwrap_or(Duration::from_secs(30)));
+        let mut buf = vec![0; 1024];
+        let mut telnet = TelnetCodec::new();
+        let mut pending = BytesMut::new();
+        let mut partial = BytesMut::new();
+        loop {
+            tokio::select! {
+                n = tcp_reader.read(&mut buf) => {
+                    let n = n.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
 
-                let text = String::from_utf8_lossy(&buf[..n]);
-                ws_sender
-                    .send(Message::Text(text.into_owned()))
-                    .await
-                    .unwrap();
+                    pending.extend_from_slice(&buf[..n]);
+                    partial.extend_from_slice(&telnet.strip(&mut pending));
+                    let output = split_complete_utf8(&mut partial);
+                    if output.is_empty() {
+                        continue;
+                    }
+                    let text = String::from_utf8_lossy(&output);
+                    if ws_sender.send(Message::Text(text.into_owned())).await.is_err() {
+                        break;
+                    }
+                }
+                _ = ping.tick(), if ping_interval.is_some() => {
+                    let interval = ping_interval.unwrap_or_default();
+                    if last_pong.lock().await.elapsed() > interval * 3 {
+                        tracing::info!("closing websocket that stopped answering pings");
+                        let _ = ws_sender.close().await;
+                        break;
+                    }
+                    if ws_sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                }
             }
-        });
+        }
+        metrics::websocket_closed(local);
+    });
+    Ok(())
+}
+
+/// Splits off everything but a character cut off at the end of `buf`,
+/// which stays there until the rest of it is read.
+fn split_complete_utf8(buf: &mut BytesMut) -> BytesMut {
+    let mut cut = buf.len();
+    for (i, &byte) in buf.iter().enumerate().rev().take(3) {
+        let width = match byte {
+            0x80..=0xbf => continue,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => break,
+        };
+        if buf.len() - i < width {
+            cut = i;
+        }
+        break;
+    }
+    buf.split_to(cut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_cut_at_the_end_wait_for_the_next_read() {
+        let text = "a↪€🦀".as_bytes();
+        for end in 0..=text.len() {
+            let mut buf = BytesMut::from(&text[..end]);
+            let complete = split_complete_utf8(&mut buf);
+            assert!(std::str::from_utf8(&complete).is_ok(), "cut at {}", end);
+            buf.extend_from_slice(&text[end..]);
+            let mut joined = complete.to_vec();
+            joined.extend_from_slice(&split_complete_utf8(&mut buf));
+            assert_eq!(joined, text);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn invalid_bytes_are_passed_on() {
+        let mut buf = BytesMut::from(&b"ok\xff\xfe"[..]);
+        assert_eq!(&split_complete_utf8(&mut buf)[..], b"ok\xff\xfe");
+        let mut buf = BytesMut::from(&b"\x80\x80\x80\x80"[..]);
+        assert_eq!(split_complete_utf8(&mut buf).len(), 4);
     }
 }